@@ -0,0 +1,99 @@
+use std::fmt;
+use std::sync::Arc;
+
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request};
+use tokio_tungstenite::tungstenite::http::{StatusCode, Uri};
+use url::form_urlencoded;
+use uuid::Uuid;
+
+/// Who a connection authenticated as, see [`crate::WebsocketServerResource::set_authenticator`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity(pub String);
+
+/// A secret handed to each client along with its id so it can prove who it is later,
+/// never send it to other clients. Resuming a session uses it up and hands out a new one
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct SessionToken(String);
+
+impl SessionToken {
+    pub(crate) fn generate() -> Self {
+        Self(Uuid::new_v4().simple().to_string())
+    }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+// keep it out of logs
+impl fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionToken(..)")
+    }
+}
+
+/// Why a connection was refused, sent back as the HTTP response to the upgrade request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub status: StatusCode,
+    pub reason: String,
+}
+
+impl Rejection {
+    pub fn new(status: StatusCode, reason: impl Into<String>) -> Self {
+        Self {
+            status,
+            reason: reason.into(),
+        }
+    }
+    /// 401, the client didn't say who it is or the credentials are wrong
+    pub fn unauthorized(reason: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, reason)
+    }
+    /// 403, the client is known but not allowed in
+    pub fn forbidden(reason: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, reason)
+    }
+
+    pub(crate) fn into_response(self) -> ErrorResponse {
+        let mut response = ErrorResponse::new(Some(self.reason));
+        *response.status_mut() = self.status;
+        response
+    }
+}
+
+/// The HTTP request a client sent to open a websocket
+pub struct UpgradeRequest<'a>(pub &'a Request);
+
+impl<'a> UpgradeRequest<'a> {
+    /// First value of the query string parameter `name`, decoded
+    pub fn query(&self, name: &str) -> Option<String> {
+        query(self.0.uri(), name)
+    }
+    /// Value of the header `name`, `None` if it's missing or not valid text
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.0.headers().get(name)?.to_str().ok()
+    }
+    /// Value of the cookie `name` from the cookie headers
+    pub fn cookie(&self, name: &str) -> Option<&'a str> {
+        self.0
+            .headers()
+            .get_all("cookie")
+            .iter()
+            .filter_map(|header| header.to_str().ok())
+            .flat_map(|header| header.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Decides whether an upgrade request may connect and who it is
+pub type Authenticator =
+    Arc<dyn Fn(&UpgradeRequest) -> Result<Identity, Rejection> + Send + Sync + 'static>;
+
+// first value of the query string parameter `name` in `uri`, decoded
+pub(crate) fn query(uri: &Uri, name: &str) -> Option<String> {
+    form_urlencoded::parse(uri.query()?.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
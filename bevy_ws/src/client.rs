@@ -1,4 +1,5 @@
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use async_compat::Compat;
 use bevy::prelude::*;
@@ -9,35 +10,61 @@ use futures_util::StreamExt;
 use log::info;
 use log::trace;
 use log::warn;
-use tokio_tungstenite::connect_async;
-use tokio_tungstenite::tungstenite::Message;
-use url::Url;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::http::{self, header::SEC_WEBSOCKET_PROTOCOL};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
 
-use crate::WebsocketClientEvent;
+use crate::settings::WebsocketSettings;
+use crate::traffic::{ClientDiagnostics, TrafficCounters, TrafficStats};
+use crate::transport::{TcpTransport, Transport};
+use crate::{parse_welcome_message, session_protocol, WebsocketClientEvent};
 
 pub struct WebsocketPlugin;
 
-struct MessageOffset(usize); // todo remove
-
 impl Plugin for WebsocketPlugin {
     fn build(&self, app: &mut App) {
+        app.world
+            .get_resource_or_insert_with(WebsocketSettings::default);
         app.insert_resource(WebsocketResource::default());
         app.add_event::<WebsocketClientEvent>();
-        app.add_system(setup_websocket_system.system());
-        app.insert_resource(MessageOffset(0));
-        app.add_system(write_websocket_event_to_client.system());
+        app.add_event::<ClientDiagnostics>();
+        app.add_system(setup_websocket_system);
+        app.add_system(write_websocket_event_to_client);
+        app.add_system(send_client_diagnostics);
     }
 }
 
+fn send_client_diagnostics(
+    resource: Res<WebsocketResource>,
+    settings: Res<WebsocketSettings>,
+    mut last_sent: Local<Option<Instant>>,
+    mut diagnostics: EventWriter<ClientDiagnostics>,
+) {
+    let interval = match settings.diagnostics_interval {
+        Some(interval) => interval,
+        None => return,
+    };
+    let last = *last_sent.get_or_insert_with(Instant::now);
+    if last.elapsed() < interval {
+        return;
+    }
+    *last_sent = Some(Instant::now());
+    diagnostics.send(ClientDiagnostics(resource.traffic()));
+}
+
 fn write_websocket_event_to_client(
     mut event_writer: EventWriter<WebsocketClientEvent>,
     resource: ResMut<WebsocketResource>,
+    settings: Res<WebsocketSettings>,
 ) {
     let mut receiver = resource.ws_to_event_channel_receiver.lock().unwrap();
     match &mut *receiver {
         Some(receiver) => match receiver.try_recv() {
             Ok(msg) => {
-                info!("system pusing event {:?} to client", msg);
+                if settings.log_payloads {
+                    info!("system pusing event {:?} to client", msg);
+                }
                 event_writer.send(msg);
             }
             Err(e) => trace!("error reading next event to be pushed {:?}", e),
@@ -47,18 +74,23 @@ fn write_websocket_event_to_client(
     trace!("done with push system");
 }
 
-fn setup_websocket_system(mut resource: ResMut<WebsocketResource>, task_pool: Res<IoTaskPool>) {
+fn setup_websocket_system(
+    mut resource: ResMut<WebsocketResource>,
+    task_pool: Res<IoTaskPool>,
+    settings: Res<WebsocketSettings>,
+) {
     if let Some(address) = resource.address.take() {
         info!("Have an address to connect to: {address}");
         let (ws_to_event_channel_sender, ws_to_event_channel_receiver) =
-            tokio::sync::mpsc::channel::<WebsocketClientEvent>(10);
+            tokio::sync::mpsc::channel::<WebsocketClientEvent>(settings.event_queue_size);
         resource
             .ws_to_event_channel_receiver
             .lock()
             .unwrap()
             .replace(ws_to_event_channel_receiver);
 
-        let (send_ws, mut message_to_send) = tokio::sync::mpsc::channel::<WebsocketClientEvent>(10);
+        let (send_ws, mut message_to_send) =
+            tokio::sync::mpsc::channel::<WebsocketClientEvent>(settings.send_queue_size);
         resource
             .message_to_be_sent_over_ws
             .lock()
@@ -72,53 +104,92 @@ fn setup_websocket_system(mut resource: ResMut<WebsocketResource>, task_pool: Re
             .unwrap()
             .replace(shutdown_main_loop);
 
+        // every connection gets its own flag so closing the last one doesn't stop a reopened one
+        resource.run_listen_loop = Arc::new(Mutex::new(true));
         let running = resource.run_listen_loop.clone();
+        resource.send_queue_size = settings.send_queue_size;
+        let traffic = resource.traffic.clone();
+        let session_token = resource.session_token.clone();
+        let settings = settings.clone();
+        let connect = match upgrade_request(&address, resource.resume_token.take()) {
+            Ok(request) => resource
+                .transport
+                .connect(request, settings.clone(), task_pool.clone()),
+            Err(e) => futures::future::ready(Err(e)).boxed(),
+        };
         // create a separate task that will both listen to ws messages
         // and also take messages to send and send them
         task_pool.spawn(Compat::new(async move {
-            let url = Url::parse(address.as_str()).unwrap();
-            let (ws_stream, _) = connect_async(url).await.expect("Failed to connect");
+            let ws_stream = match connect.await {
+                Ok(ws_stream) => ws_stream,
+                Err(e) => {
+                    warn!("Failed to connect to {}: {:?}", address, e);
+                    ws_to_event_channel_sender.send(WebsocketClientEvent::OnClose).await
+                        .unwrap_or_else(|e|warn!("Failed to report close:{:?}", e));
+                    return;
+                }
+            };
             let (mut write, mut read) = ws_stream.split();
 
             let listen_for_shutdown = listen_for_shutdown.fuse();
             futures::pin_mut!(listen_for_shutdown);
 
+            let mut heartbeat = settings.heartbeat_interval.map(|period| {
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period)
+            });
+
             'main: while *running.lock().expect("lock") {
                 trace!("loop");
                 let next_incoming_message = read.next().fuse();
                 let next_message_to_send = message_to_send.recv().fuse();
-                futures::pin_mut!(next_incoming_message, next_message_to_send);
+                let next_heartbeat = next_heartbeat(&mut heartbeat).fuse();
+                futures::pin_mut!(next_incoming_message, next_message_to_send, next_heartbeat);
                 futures::select! {
                     msg = next_incoming_message  => {
                         if let Some(result) = msg {
                             match result {
                                 Ok(Message::Text(msg)) => {
+                                    traffic.received(msg.len());
                                     let str = msg.to_string();
-                                    info!("Client received: {:?}", str);
-                                    if str.contains("##CLIENT_ID##") {
-                                        let mut split = str.split(" ");
-                                        split.next();
-                                        let id = split.next();
-                                        let id = id.unwrap();
-                                        ws_to_event_channel_sender.send(WebsocketClientEvent::OnOpen(id.parse().unwrap())).await.unwrap();
+                                    if settings.log_payloads {
+                                        info!("Client received: {:?}", str);
+                                    }
+                                    if let Some((id, token)) = parse_welcome_message(&str) {
+                                        *session_token.lock().expect("lock") = token;
+                                        ws_to_event_channel_sender.send(WebsocketClientEvent::OnOpen(id)).await.unwrap();
                                     } else {
                                         ws_to_event_channel_sender.send(WebsocketClientEvent::OnMessage(msg)).await.unwrap();
                                     }
                                 },
+                                Ok(Message::Close(Some(frame))) => {
+                                    info!("Server is closing the connection: {}", frame.reason);
+                                }
                                 Err(e) => log::info!("ws recv error: {:?}", e),
                         _ => {},
                             }
+                        } else {
+                            warn!("Server closed the connection");
+                            ws_to_event_channel_sender.send(WebsocketClientEvent::OnClose).await
+                                .unwrap_or_else(|e|warn!("Failed to report close:{:?}", e));
+                            break 'main;
                         }
                     },
                     msg = next_message_to_send => {
-                        if let Some(msg) = msg {
-                            if let WebsocketClientEvent::OnMessage(str) = msg {
-                                  write.send(Message::Text(str)).await
-                                .unwrap_or_else(|e|warn!("Send failed:{:?}", e));
+                        if let Some(WebsocketClientEvent::OnMessage(str)) = msg {
+                            let bytes = str.len();
+                            match write.send(Message::Text(str)).await {
+                                Ok(()) => traffic.sent(bytes),
+                                Err(e) => {
+                                    warn!("Send failed:{:?}", e);
+                                    traffic.dropped();
+                                }
                             }
-
                         }
                     },
+                    _ = next_heartbeat => {
+                        write.send(Message::Ping(vec![])).await
+                            .unwrap_or_else(|e|warn!("Ping failed:{:?}", e));
+                    },
                     _shutdown = listen_for_shutdown => {
                         warn!("Got shutdown signal");
                         break 'main;
@@ -130,24 +201,61 @@ fn setup_websocket_system(mut resource: ResMut<WebsocketResource>, task_pool: Re
     }
 }
 
+// the upgrade request for `address`, offering the session to resume if there is one.
+// The error type is dictated by the transports
+#[allow(clippy::result_large_err)]
+fn upgrade_request(address: &str, resume_token: Option<String>) -> Result<Request, WsError> {
+    let mut request = address.into_client_request()?;
+    if let Some(token) = resume_token {
+        let protocol = session_protocol(&token)
+            .parse()
+            .map_err(|e| WsError::HttpFormat(http::Error::from(e)))?;
+        request
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_PROTOCOL, protocol);
+    }
+    Ok(request)
+}
+
+async fn next_heartbeat(heartbeat: &mut Option<tokio::time::Interval>) {
+    match heartbeat {
+        Some(heartbeat) => {
+            heartbeat.tick().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
 // use by the client to talk to server
 pub struct WebsocketResource {
     address: Option<String>,
+    // the session the next connection asks to resume
+    resume_token: Option<String>,
     pub ws_to_event_channel_receiver:
         Mutex<Option<tokio::sync::mpsc::Receiver<WebsocketClientEvent>>>,
     pub message_to_be_sent_over_ws: Mutex<Option<tokio::sync::mpsc::Sender<WebsocketClientEvent>>>,
     pub shutdown_main_loop: Mutex<Option<tokio::sync::oneshot::Sender<()>>>,
     run_listen_loop: Arc<Mutex<bool>>,
+    transport: Arc<dyn Transport>,
+    session_token: Arc<Mutex<Option<String>>>,
+    // every connection since the resource was created
+    traffic: Arc<TrafficCounters>,
+    send_queue_size: usize,
 }
 
 impl Default for WebsocketResource {
     fn default() -> Self {
         Self {
             address: None,
+            resume_token: None,
             ws_to_event_channel_receiver: Default::default(),
             message_to_be_sent_over_ws: Default::default(),
             shutdown_main_loop: Default::default(),
             run_listen_loop: Arc::new(Mutex::new(true)),
+            transport: Arc::new(TcpTransport::default()),
+            session_token: Default::default(),
+            traffic: Default::default(),
+            send_queue_size: 0,
         }
     }
 }
@@ -155,20 +263,49 @@ impl Default for WebsocketResource {
 impl WebsocketResource {
     pub fn open(&mut self, address: &str) {
         self.address = Some(address.to_string());
+        self.resume_token = None;
+    }
+    /// Opens `address` again presenting the session token from the last connection so the
+    /// server hands back the same client id, see
+    /// [`crate::WebsocketServerSettings::session_grace_period`]
+    pub fn resume(&mut self, address: &str) {
+        self.open(address);
+        self.resume_token = self.session_token();
+    }
+    /// Replaces how the connection is opened, [`TcpTransport`] by default.
+    /// Takes effect the next time [`Self::open`] is used
+    pub fn set_transport(&mut self, transport: impl Transport) {
+        self.transport = Arc::new(transport);
+    }
+    /// The secret the server handed out with our client id, only share it with the server
+    pub fn session_token(&self) -> Option<String> {
+        self.session_token.lock().unwrap().clone()
     }
     pub fn broadcast(&self, message: String) {
         let mut receiver = self.message_to_be_sent_over_ws.lock().unwrap();
         match &mut *receiver {
             Some(receiver) => {
-                info!("Added {:?} to message_to_be_sent_over_ws", message);
-                receiver
-                    .blocking_send(WebsocketClientEvent::OnMessage(message))
-                    .unwrap_or_else(|e| warn!("Broadcast failed:{:?}", e));
-                return;
+                trace!("Added {:?} to message_to_be_sent_over_ws", message);
+                if let Err(e) = receiver.blocking_send(WebsocketClientEvent::OnMessage(message)) {
+                    warn!("Broadcast failed:{:?}", e);
+                    self.traffic.dropped();
+                }
             }
             None => panic!("no sender"),
         }
     }
+    /// What went over every connection this resource opened
+    pub fn traffic(&self) -> TrafficStats {
+        let queued = self
+            .message_to_be_sent_over_ws
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |sender| {
+                self.send_queue_size.saturating_sub(sender.capacity())
+            });
+        self.traffic.stats(queued)
+    }
     pub fn close(&self) {
         *self.run_listen_loop.lock().unwrap() = false;
         self.message_to_be_sent_over_ws.lock().unwrap().take();
@@ -181,7 +318,6 @@ impl WebsocketResource {
                 receiver
                     .send(())
                     .unwrap_or_else(|e| warn!("Shutdown signal failed:{:?}", e));
-                return;
             }
             None => panic!("no sender"),
         }
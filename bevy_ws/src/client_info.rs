@@ -0,0 +1,127 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio_tungstenite::tungstenite::http::{HeaderMap, Uri};
+
+use crate::auth::{query, Identity, SessionToken};
+use crate::traffic::TrafficCounters;
+
+/// What the server knows about a connected client, see
+/// [`crate::WebsocketServerResource::client`]
+pub struct ClientInfo {
+    /// The address the connection came from, a proxy's if there is one in front of the server.
+    /// `None` for transports without addresses
+    pub peer_addr: Option<SocketAddr>,
+    /// The client's own address, taken from `X-Forwarded-For` when `peer_addr` is one of
+    /// [`crate::WebsocketServerSettings::trusted_proxies`]
+    pub remote_addr: Option<IpAddr>,
+    pub connected_at: Instant,
+    /// The path and query string of the upgrade request, see [`Self::query`]
+    pub uri: Uri,
+    /// The headers of the upgrade request
+    pub headers: HeaderMap,
+    pub identity: Option<Identity>,
+    /// Let in over [`crate::WebsocketServerResource::set_max_clients`], doesn't count towards it
+    pub spectator: bool,
+    session_token: SessionToken,
+    pub(crate) traffic: Arc<TrafficCounters>,
+    user_data: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl ClientInfo {
+    pub(crate) fn new(
+        peer_addr: Option<SocketAddr>,
+        uri: Uri,
+        headers: HeaderMap,
+        identity: Option<Identity>,
+        session_token: SessionToken,
+        traffic: TrafficCounters,
+        trusted_proxies: &[IpAddr],
+    ) -> Self {
+        Self {
+            peer_addr,
+            remote_addr: remote_addr(peer_addr, &headers, trusted_proxies),
+            connected_at: Instant::now(),
+            uri,
+            headers,
+            identity,
+            spectator: false,
+            session_token,
+            traffic: Arc::new(traffic),
+            user_data: Default::default(),
+        }
+    }
+    // a resumed session keeps its identity and user data but is now somewhere else, and gets a
+    // new token so the old one can't be used again
+    pub(crate) fn reconnected(
+        &mut self,
+        peer_addr: Option<SocketAddr>,
+        uri: Uri,
+        headers: HeaderMap,
+        trusted_proxies: &[IpAddr],
+    ) {
+        self.session_token = SessionToken::generate();
+        self.peer_addr = peer_addr;
+        self.remote_addr = remote_addr(peer_addr, &headers, trusted_proxies);
+        self.connected_at = Instant::now();
+        self.uri = uri;
+        self.headers = headers;
+    }
+    /// First value of the query string parameter `name` the client connected with, decoded
+    pub fn query(&self, name: &str) -> Option<String> {
+        query(&self.uri, name)
+    }
+    /// The secret the client got with its id, see [`SessionToken`]
+    pub fn session_token(&self) -> &SessionToken {
+        &self.session_token
+    }
+    /// Attaches `data` to the client, replacing anything of the same type
+    pub fn insert<T: Any + Send + Sync>(&mut self, data: T) {
+        self.user_data.insert(TypeId::of::<T>(), Box::new(data));
+    }
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.user_data.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+    pub fn get_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.user_data.get_mut(&TypeId::of::<T>())?.downcast_mut()
+    }
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        let data = self.user_data.remove(&TypeId::of::<T>())?;
+        data.downcast().ok().map(|data| *data)
+    }
+}
+
+// every proxy appends the address it got the request from, so walking back from our peer
+// the first address that isn't one of our proxies is the client. Anything before that
+// could have been made up by the client
+fn remote_addr(
+    peer_addr: Option<SocketAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let peer = peer_addr?.ip();
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+    let mut forwarded_for = vec![];
+    for header in headers.get_all("x-forwarded-for") {
+        let header = match header.to_str() {
+            Ok(header) => header,
+            Err(_) => return Some(peer),
+        };
+        for address in header.split(',') {
+            match address.trim().parse::<IpAddr>() {
+                Ok(address) => forwarded_for.push(address),
+                Err(_) => return Some(peer),
+            }
+        }
+    }
+    let client = forwarded_for
+        .into_iter()
+        .rev()
+        .find(|address| !trusted_proxies.contains(address));
+    Some(client.unwrap_or(peer))
+}
@@ -0,0 +1,344 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use async_compat::Compat;
+use bevy::tasks::IoTaskPool;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::future::BoxFuture;
+use futures::stream::FusedStream;
+use futures::{FutureExt, Sink, SinkExt, Stream, StreamExt};
+use log::trace;
+use log::warn;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+use crate::settings::{WebsocketServerSettings, WebsocketSettings};
+use crate::transport::{Connection, Listener, Transport};
+
+// held back messages wait at least this long so later ones can overtake them
+const MIN_REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// How bad a simulated connection is, applied to each direction of a connection separately
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkConditions {
+    /// Added to every message
+    pub latency: Duration,
+    /// Up to this much is randomly added on top of the latency
+    pub jitter: Duration,
+    /// Chance from 0 to 1 that a message is dropped, anything outside that is clamped
+    pub loss: f64,
+    /// Chance from 0 to 1 that a message is delivered twice, anything outside that is clamped
+    pub duplicate: f64,
+    /// Chance from 0 to 1 that a message is held back so the ones after it arrive first,
+    /// anything outside that is clamped
+    pub reorder: f64,
+    /// Makes the random choices repeatable, `None` picks a new seed for every connection
+    pub seed: Option<u64>,
+}
+
+impl NetworkConditions {
+    /// The names accepted by [`NetworkConditions::from_str`]
+    pub const PROFILES: [&'static str; 5] = ["perfect", "lan", "broadband", "mobile", "terrible"];
+
+    pub fn perfect() -> Self {
+        Self::default()
+    }
+    pub fn lan() -> Self {
+        Self {
+            latency: Duration::from_millis(2),
+            jitter: Duration::from_millis(1),
+            ..Self::default()
+        }
+    }
+    pub fn broadband() -> Self {
+        Self {
+            latency: Duration::from_millis(30),
+            jitter: Duration::from_millis(5),
+            loss: 0.001,
+            ..Self::default()
+        }
+    }
+    pub fn mobile() -> Self {
+        Self {
+            latency: Duration::from_millis(80),
+            jitter: Duration::from_millis(30),
+            loss: 0.02,
+            duplicate: 0.01,
+            reorder: 0.01,
+            ..Self::default()
+        }
+    }
+    pub fn terrible() -> Self {
+        Self {
+            latency: Duration::from_millis(200),
+            jitter: Duration::from_millis(100),
+            loss: 0.1,
+            duplicate: 0.05,
+            reorder: 0.05,
+            ..Self::default()
+        }
+    }
+
+    fn reorder_delay(&self) -> Duration {
+        self.latency.max(MIN_REORDER_DELAY)
+    }
+
+    // when each copy of `message` should be delivered, empty if it's lost
+    fn plan(&self, message: &Message, rng: &mut StdRng) -> Vec<Duration> {
+        match message {
+            Message::Text(_) | Message::Binary(_) => {
+                if chance(rng, self.loss) {
+                    return vec![];
+                }
+                let copies = if chance(rng, self.duplicate) { 2 } else { 1 };
+                (0..copies)
+                    .map(|_| {
+                        let mut delay = self.latency + self.jitter.mul_f64(rng.gen());
+                        if chance(rng, self.reorder) {
+                            delay += self.reorder_delay();
+                        }
+                        delay
+                    })
+                    .collect()
+            }
+            // a close shouldn't overtake the messages sent before it
+            Message::Close(_) => vec![self.latency + self.jitter + self.reorder_delay()],
+            // control frames are never lost, websockets don't lose anything
+            _ => vec![self.latency],
+        }
+    }
+}
+
+// true with `probability`, which is clamped to 0 to 1, NaN never
+fn chance(rng: &mut StdRng, probability: f64) -> bool {
+    probability > 0.0 && (probability >= 1.0 || rng.gen_bool(probability))
+}
+
+#[derive(Debug)]
+pub struct UnknownProfile(String);
+
+impl fmt::Display for UnknownProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown network profile '{}', expected one of {}",
+            self.0,
+            NetworkConditions::PROFILES.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnknownProfile {}
+
+impl FromStr for NetworkConditions {
+    type Err = UnknownProfile;
+
+    fn from_str(profile: &str) -> Result<Self, Self::Err> {
+        match profile {
+            "perfect" => Ok(Self::perfect()),
+            "lan" => Ok(Self::lan()),
+            "broadband" => Ok(Self::broadband()),
+            "mobile" => Ok(Self::mobile()),
+            "terrible" => Ok(Self::terrible()),
+            _ => Err(UnknownProfile(profile.to_string())),
+        }
+    }
+}
+
+/// Wraps another transport and runs every connection through [`condition`]
+pub struct ConditionedTransport<T> {
+    inner: T,
+    conditions: NetworkConditions,
+    connections: Arc<AtomicU64>,
+}
+
+impl<T: Transport> ConditionedTransport<T> {
+    pub fn new(inner: T, conditions: NetworkConditions) -> Self {
+        Self {
+            inner,
+            conditions,
+            connections: Default::default(),
+        }
+    }
+}
+
+impl<T: Transport> Transport for ConditionedTransport<T> {
+    fn listen(
+        &self,
+        address: String,
+        listener: Listener,
+        settings: WebsocketServerSettings,
+        task_pool: IoTaskPool,
+    ) {
+        let conditions = self.conditions.clone();
+        let connections = self.connections.clone();
+        let pump_pool = task_pool.clone();
+        let listener = listener.map(move |connection| {
+            let rng = connection_rng(&conditions, &connections);
+            condition(connection, conditions.clone(), rng, &pump_pool)
+        });
+        self.inner.listen(address, listener, settings, task_pool);
+    }
+
+    fn connect(
+        &self,
+        request: Request,
+        settings: WebsocketSettings,
+        task_pool: IoTaskPool,
+    ) -> BoxFuture<'static, Result<Connection, WsError>> {
+        let conditions = self.conditions.clone();
+        let rng = connection_rng(&conditions, &self.connections);
+        let connect = self.inner.connect(request, settings, task_pool.clone());
+        async move {
+            let connection = connect.await?;
+            Ok(condition(connection, conditions, rng, &task_pool))
+        }
+        .boxed()
+    }
+}
+
+// every connection gets its own generator, still repeatable when there's a seed
+fn connection_rng(conditions: &NetworkConditions, connections: &AtomicU64) -> StdRng {
+    let index = connections.fetch_add(1, Ordering::SeqCst);
+    match conditions.seed {
+        Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(index)),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/// Runs both directions of `connection` through `conditions`, delaying, dropping,
+/// duplicating and reordering messages. The work is done by tasks on `task_pool`
+pub fn condition(
+    connection: Connection,
+    conditions: NetworkConditions,
+    mut rng: StdRng,
+    task_pool: &IoTaskPool,
+) -> Connection {
+    let (inner_send, inner_receive) = connection.split();
+    let (to_user, incoming) = unbounded();
+    let (outgoing, from_user) = unbounded();
+    let inbound_rng = StdRng::seed_from_u64(rng.gen());
+    let inbound_conditions = conditions.clone();
+    task_pool
+        .spawn(Compat::new(async move {
+            pump(inner_receive, to_user, inbound_conditions, inbound_rng).await;
+            trace!("conditioned inbound loop closing");
+        }))
+        .detach();
+    task_pool
+        .spawn(Compat::new(async move {
+            let inner_send = inner_send.with(futures::future::ready);
+            pump(from_user.map(Ok), inner_send, conditions, rng).await;
+            trace!("conditioned outbound loop closing");
+        }))
+        .detach();
+    Box::pin(ConditionedConnection { incoming, outgoing })
+}
+
+// moves messages from `source` to `destination` according to `conditions`, errors are passed
+// on with the latency. Keeps going until `source` ends and everything in flight was delivered
+async fn pump<S, D>(source: S, mut destination: D, conditions: NetworkConditions, mut rng: StdRng)
+where
+    S: Stream<Item = Result<Message, WsError>> + Unpin,
+    D: Sink<Result<Message, WsError>> + Unpin,
+    D::Error: fmt::Debug,
+{
+    let mut source = source.fuse();
+    // keyed on arrival time and then send order so equal delays keep their order
+    let mut in_flight: BTreeMap<(Instant, u64), Result<Message, WsError>> = BTreeMap::new();
+    let mut sent = 0u64;
+    loop {
+        let now = Instant::now();
+        while let Some(entry) = in_flight.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            if let Err(e) = destination.send(entry.remove()).await {
+                trace!("conditioned destination closed: {:?}", e);
+                return;
+            }
+        }
+        if source.is_terminated() && in_flight.is_empty() {
+            break;
+        }
+        let next_due = until(in_flight.keys().next().map(|(due, _)| *due)).fuse();
+        futures::pin_mut!(next_due);
+        futures::select! {
+            msg = source.next() => {
+                match msg {
+                    Some(Ok(msg)) => {
+                        for delay in conditions.plan(&msg, &mut rng) {
+                            in_flight.insert((now + delay, sent), Ok(msg.clone()));
+                            sent += 1;
+                        }
+                    }
+                    // like a size limit, the other end has to hear about those
+                    Some(Err(e)) => {
+                        warn!("conditioned connection error: {:?}", e);
+                        in_flight.insert((now + conditions.latency, sent), Err(e));
+                        sent += 1;
+                    }
+                    None => {}
+                }
+            },
+            _ = next_due => {},
+        }
+    }
+    destination
+        .close()
+        .await
+        .unwrap_or_else(|e| trace!("Close failed:{:?}", e));
+}
+
+async fn until(due: Option<Instant>) {
+    match due {
+        Some(due) => {
+            async_io::Timer::at(due).await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
+struct ConditionedConnection {
+    incoming: UnboundedReceiver<Result<Message, WsError>>,
+    outgoing: UnboundedSender<Message>,
+}
+
+impl Stream for ConditionedConnection {
+    type Item = Result<Message, WsError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_next_unpin(cx)
+    }
+}
+
+impl Sink<Message> for ConditionedConnection {
+    type Error = WsError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        self.outgoing
+            .unbounded_send(item)
+            .map_err(|_| WsError::AlreadyClosed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.outgoing.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
@@ -35,6 +35,7 @@ impl<T> EventStream<T> {
         })
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Option<T> {
         self.buffer
             .lock()
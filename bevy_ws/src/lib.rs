@@ -1,21 +1,50 @@
-#[cfg(not(feature = "web"))]
+#[cfg(all(feature = "native", feature = "web"))]
+compile_error!("features \"native\" and \"web\" can't be enabled at the same time");
+
+#[cfg(feature = "native")]
+pub use auth::{Identity, Rejection, SessionToken, UpgradeRequest};
+#[cfg(feature = "native")]
 pub use client::WebsocketPlugin;
-#[cfg(not(feature = "web"))]
+#[cfg(feature = "native")]
 pub use client::WebsocketResource;
-#[cfg(not(feature = "web"))]
+#[cfg(feature = "native")]
+pub use client_info::ClientInfo;
+#[cfg(feature = "native")]
+pub use conditioner::{ConditionedTransport, NetworkConditions};
+#[cfg(feature = "native")]
 pub use server::WebsocketServerPlugin;
-#[cfg(not(feature = "web"))]
+#[cfg(feature = "native")]
 pub use server::WebsocketServerResource;
+#[cfg(feature = "native")]
+pub use server::{ConnectionCount, Overflow, SERVER_FULL};
+pub use settings::{RateLimit, StaticFiles, WebsocketServerSettings, WebsocketSettings};
+pub use traffic::{ClientDiagnostics, ServerDiagnostics, TrafficStats};
+#[cfg(feature = "native")]
+pub use transport::{LoopbackTransport, TcpTransport, TlsError, Transport};
 #[cfg(feature = "web")]
 pub use ws_client::WebsocketPlugin;
 #[cfg(feature = "web")]
 pub use ws_client::WebsocketResource;
 
-#[cfg(not(feature = "web"))]
+#[cfg(feature = "native")]
+pub mod auth;
+#[cfg(feature = "native")]
 pub mod client;
+#[cfg(feature = "native")]
+pub mod client_info;
+#[cfg(feature = "native")]
+pub mod conditioner;
 pub mod event_stream;
-#[cfg(not(feature = "web"))]
+#[cfg(feature = "native")]
+mod rate_limit;
+#[cfg(feature = "native")]
 pub mod server;
+pub mod settings;
+#[cfg(feature = "native")]
+mod static_files;
+pub mod traffic;
+#[cfg(feature = "native")]
+pub mod transport;
 #[cfg(feature = "web")]
 pub mod ws_client;
 
@@ -23,7 +52,26 @@ pub mod ws_client;
 pub enum WebsocketServerEvent {
     OnOpen(u64),
     OnMessage(u64, String),
+    /// The client is gone for good
     OnClose(u64),
+    /// The connection dropped but the client may still [`WebsocketServerEvent::OnResume`] within
+    /// [`WebsocketServerSettings::session_grace_period`], otherwise it's followed by an `OnClose`
+    OnDisconnect(u64),
+    /// A disconnected client came back with its session token and kept its id
+    OnResume(u64),
+    /// A message from the client was dropped for going over a limit
+    LimitExceeded(u64, Limit),
+    /// Sent once after a shutdown has closed every connection and the listener
+    ServerStopped,
+}
+
+/// The limits from [`WebsocketServerSettings`] a client can go over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// [`WebsocketServerSettings::max_message_size`]
+    MessageSize,
+    /// [`WebsocketServerSettings::rate_limit`]
+    Rate,
 }
 
 #[derive(Debug, Clone)]
@@ -33,7 +81,45 @@ pub enum WebsocketClientEvent {
     OnClose,
 }
 
-#[cfg(test)]
+// first message on every connection, tells the client its id and session token
+const WELCOME_PREFIX: &str = "##CLIENT_ID##";
+
+#[cfg(feature = "native")]
+pub(crate) fn welcome_message(client_id: u64, session_token: &str) -> String {
+    format!("{} {} {}", WELCOME_PREFIX, client_id, session_token)
+}
+
+// how a reconnecting client tells the server which session to resume, offered as a websocket
+// subprotocol that the server echoes back. Browsers can't set other headers and the url ends up
+// in logs
+const SESSION_PROTOCOL_PREFIX: &str = "bevy_ws.session.";
+
+pub(crate) fn session_protocol(session_token: &str) -> String {
+    format!("{}{}", SESSION_PROTOCOL_PREFIX, session_token)
+}
+
+// the session token offered in the `Sec-WebSocket-Protocol` header of an upgrade request
+#[cfg(feature = "native")]
+pub(crate) fn requested_session(
+    headers: &tokio_tungstenite::tungstenite::http::HeaderMap,
+) -> Option<String> {
+    headers
+        .get_all(tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|protocol| protocol.trim().strip_prefix(SESSION_PROTOCOL_PREFIX))
+        .map(str::to_string)
+}
+
+// the client id and session token if `message` is the welcome message
+pub(crate) fn parse_welcome_message(message: &str) -> Option<(u64, Option<String>)> {
+    let mut split = message.strip_prefix(WELCOME_PREFIX)?.split_whitespace();
+    let client_id = split.next()?.parse().ok()?;
+    Some((client_id, split.next().map(str::to_string)))
+}
+
+#[cfg(all(test, feature = "native"))]
 mod tests {
     use log::LevelFilter;
 
@@ -1,53 +1,163 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::ops::DerefMut;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use async_compat::Compat;
 use bevy::prelude::*;
 use bevy::tasks::IoTaskPool;
-use futures::task::Poll;
-use futures::{SinkExt, StreamExt};
+use futures::{FutureExt, SinkExt, StreamExt};
 use log::info;
 use log::trace;
 use log::warn;
-use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::{accept_async, WebSocketStream};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
 
+use crate::auth::{Authenticator, Identity, Rejection, SessionToken, UpgradeRequest};
+use crate::client_info::ClientInfo;
 use crate::event_stream::EventStream;
-use crate::{WebsocketClientEvent, WebsocketServerEvent};
+use crate::rate_limit::TokenBucket;
+use crate::settings::WebsocketServerSettings;
+use crate::traffic::{ServerDiagnostics, TrafficCounters, TrafficStats};
+use crate::transport::{
+    Connection, IncomingConnection, Listener, TcpTransport, TlsError, Transport,
+};
+use crate::{welcome_message, Limit, WebsocketServerEvent};
 
 pub struct WebsocketServerPlugin;
 
 impl Plugin for WebsocketServerPlugin {
     fn build(&self, app: &mut App) {
+        app.world
+            .get_resource_or_insert_with(WebsocketServerSettings::default);
         app.insert_resource(WebsocketServerResource::default());
+        app.init_resource::<ConnectionCount>();
         app.add_event::<WebsocketServerEvent>();
-        app.add_system(write_websocket_event_to_server.system());
-        app.add_system(websocket_server_system.system());
+        app.add_event::<ServerDiagnostics>();
+        app.add_system(write_websocket_event_to_server);
+        app.add_system(send_server_diagnostics);
+        app.add_system(websocket_server_system);
     }
 }
 
 fn write_websocket_event_to_server(
     mut event_writer: EventWriter<WebsocketServerEvent>,
-    server: ResMut<WebsocketServerResource>,
+    mut server: ResMut<WebsocketServerResource>,
+    mut connection_count: ResMut<ConnectionCount>,
+    settings: Res<WebsocketServerSettings>,
 ) {
-    let mut receivers = server.ws_to_event_channel_receiver.lock().unwrap();
-    let receivers = receivers.deref_mut();
-    for receiver in receivers {
+    let server = server.deref_mut();
+    // systems had a frame to handle the close, now the client can be forgotten
+    for client_id in std::mem::take(&mut server.closed_clients) {
+        server.clients.remove(&client_id);
+        server.leave_all_groups(client_id);
+        server
+            .message_to_be_sent_to_client_over_ws
+            .lock()
+            .unwrap()
+            .remove(&client_id);
+    }
+    let mut closed_clients = vec![];
+    let now = Instant::now();
+    let expired: Vec<u64> = server
+        .suspended
+        .iter()
+        .filter(|(_, deadline)| **deadline <= now)
+        .map(|(client_id, _)| *client_id)
+        .collect();
+    for client_id in expired {
+        info!("Session of client {} expired", client_id);
+        server.suspended.remove(&client_id);
+        closed_clients.push(client_id);
+        event_writer.send(WebsocketServerEvent::OnClose(client_id));
+    }
+    // dropped connections are only kept around while the server is running normally
+    let resumable = !settings.session_grace_period.is_zero()
+        && matches!(server.state, WsServerState::Connected);
+    for receiver in server
+        .ws_to_event_channel_receiver
+        .lock()
+        .unwrap()
+        .deref_mut()
+    {
         match receiver.try_recv() {
             Ok(msg) => {
-                info!("system pusing event {:?} to server", msg);
+                if settings.log_payloads {
+                    info!("system pusing event {:?} to server", msg);
+                }
+                let msg = match msg {
+                    WebsocketServerEvent::OnClose(client_id)
+                        if resumable && !server.kicked.lock().unwrap().remove(&client_id) =>
+                    {
+                        info!("Client {} disconnected, keeping its session", client_id);
+                        server
+                            .suspended
+                            .insert(client_id, now + settings.session_grace_period);
+                        server
+                            .message_to_be_sent_to_client_over_ws
+                            .lock()
+                            .unwrap()
+                            .remove(&client_id);
+                        WebsocketServerEvent::OnDisconnect(client_id)
+                    }
+                    WebsocketServerEvent::OnClose(client_id) => {
+                        server.kicked.lock().unwrap().remove(&client_id);
+                        closed_clients.push(client_id);
+                        msg
+                    }
+                    msg => msg,
+                };
                 event_writer.send(msg);
             }
             Err(e) => trace!("error reading next event to be pushed {:?}", e),
         }
     }
+    server.closed_clients.extend(closed_clients);
+    let (spectators, players) = server
+        .current_clients()
+        .partition::<Vec<_>, _>(|(_, client)| client.spectator);
+    *connection_count = ConnectionCount {
+        connections: server.connections.load(Ordering::SeqCst),
+        players: players.len(),
+        spectators: spectators.len(),
+    };
     trace!("done with push system");
 }
 
+fn send_server_diagnostics(
+    server: Res<WebsocketServerResource>,
+    settings: Res<WebsocketServerSettings>,
+    mut last_sent: Local<Option<Instant>>,
+    mut diagnostics: EventWriter<ServerDiagnostics>,
+) {
+    let interval = match settings.diagnostics_interval {
+        Some(interval) => interval,
+        None => return,
+    };
+    let last = *last_sent.get_or_insert_with(Instant::now);
+    if last.elapsed() < interval {
+        return;
+    }
+    *last_sent = Some(Instant::now());
+    diagnostics.send(ServerDiagnostics {
+        total: server.total_traffic(),
+        clients: server
+            .clients
+            .keys()
+            .filter_map(|client_id| Some((*client_id, server.traffic(*client_id)?)))
+            .collect(),
+    });
+}
+
 fn websocket_server_system(
     task_pool: Res<IoTaskPool>,
     mut server: ResMut<WebsocketServerResource>,
+    settings: Res<WebsocketServerSettings>,
+    mut event_writer: EventWriter<WebsocketServerEvent>,
 ) {
     match server.state {
         WsServerState::WaitingToStart => {}
@@ -56,118 +166,267 @@ fn websocket_server_system(
                 .listen_address
                 .take()
                 .expect("Should be an address here");
-            let buffer = server.new_clients_event_stream.buffer();
-            let running = server.run_listen_loop.clone();
-            // Task to listen to new connections
-            task_pool
-                .spawn(Compat::new(async move {
-                    let listener = TcpListener::bind(address.as_str()).await.unwrap();
-                    while *running.lock().expect("lock") {
-                        trace!("main listen loop");
-                        let future = listener.accept();
-                        futures::pin_mut!(future);
-                        match futures::poll!(future) {
-                            Poll::Ready(Ok((stream, _addr))) => {
-                                match stream.peer_addr() {
-                                    Ok(peer) => info!("New client: {:?}", peer),
-                                    Err(e) => info!("failed to obtain peer: {:?}", e),
-                                }
-                                match accept_async(stream).await {
-                                    Ok(ws_stream) => {
-                                        let client = ws_stream;
-                                        buffer
-                                            .lock()
-                                            .expect("lock to send a new client")
-                                            .push_back(client);
-                                    }
-                                    Err(e) => info!("Failed to upgrade websocket: {:?}", e),
-                                }
-                            }
-                            _ => (),
-                        }
-                    }
-                    warn!("accept new connections loop closing");
-                }))
-                .detach();
+            // every run gets its own flags so a listener from a previous run can't be revived
+            server.run_listen_loop = Arc::new(Mutex::new(true));
+            server.bound_address = Default::default();
+            let (force_close, force_close_receiver) = tokio::sync::watch::channel(false);
+            server.force_close = force_close;
+            server.force_close_receiver = force_close_receiver;
+            server.listening = Default::default();
+            server.traffic = Default::default();
+            server.send_queue_size = settings.send_queue_size;
+            server.clients.clear();
+            server.suspended.clear();
+            server.groups.clear();
+            let listener = Listener {
+                running: server.run_listen_loop.clone(),
+                listening: server.listening.clone(),
+                bound_address: server.bound_address.clone(),
+                connections: server.connections.clone(),
+                max_connections: settings.max_connections,
+                new_connections: server.new_clients_event_stream.buffer(),
+                wrap_connection: None,
+                authenticator: server.authenticator.clone(),
+                allowed_origins: settings.allowed_origins.clone(),
+            };
+            server
+                .transport
+                .listen(address, listener, settings.clone(), task_pool.clone());
             server.state = WsServerState::Connected;
         }
         WsServerState::Connected => {
             trace!("1");
 
             if let Some(client) = server.new_clients_event_stream.next() {
-                let client_id = server.generate_next_client_id();
-                // let ws_to_event_channel_sender = server.ws_to_event_channel_sender.try_lock().unwrap().clone();
+                let resumed = client
+                    .resume_token
+                    .as_deref()
+                    .and_then(|token| server.resumable_client(token, &client.identity));
+                let mut spectator = false;
+                if resumed.is_none() && server.is_full() {
+                    match server.overflow {
+                        Overflow::Reject => {
+                            info!("Server full, turning away {:?}", client.peer_addr);
+                            reject_full(client.connection, &task_pool, settings.shutdown_timeout);
+                            return;
+                        }
+                        Overflow::Spectate => spectator = true,
+                    }
+                }
+                let (client_id, opened) = match resumed {
+                    Some(client_id) => {
+                        info!("Client {} resumed its session", client_id);
+                        server.suspended.remove(&client_id);
+                        server
+                            .clients
+                            .get_mut(&client_id)
+                            .expect("suspended clients are kept")
+                            .reconnected(
+                                client.peer_addr,
+                                client.uri,
+                                client.headers,
+                                &settings.trusted_proxies,
+                            );
+                        (client_id, WebsocketServerEvent::OnResume(client_id))
+                    }
+                    None => {
+                        let client_id = server.generate_next_client_id();
+                        if let Some(identity) = &client.identity {
+                            info!("Client {} authenticated as {:?}", client_id, identity);
+                        }
+                        let mut client_info = ClientInfo::new(
+                            client.peer_addr,
+                            client.uri,
+                            client.headers,
+                            client.identity,
+                            SessionToken::generate(),
+                            TrafficCounters::counting_towards(server.traffic.clone()),
+                            &settings.trusted_proxies,
+                        );
+                        client_info.spectator = spectator;
+                        server.clients.insert(client_id, client_info);
+                        (client_id, WebsocketServerEvent::OnOpen(client_id))
+                    }
+                };
+                let welcome = welcome_message(
+                    client_id,
+                    server.clients[&client_id].session_token().as_str(),
+                );
                 let (ws_to_event_channel_sender, ws_to_event_channel_receiver) =
-                    tokio::sync::mpsc::channel::<WebsocketServerEvent>(100);
+                    tokio::sync::mpsc::channel::<WebsocketServerEvent>(settings.event_queue_size);
                 server
                     .ws_to_event_channel_receiver
                     .lock()
                     .unwrap()
                     .push(ws_to_event_channel_receiver);
-                let (mut send, mut receive) = client.split();
-                let running = server.run_listen_loop.clone();
+                let (send_ws, mut message_to_send) =
+                    tokio::sync::mpsc::channel::<Outgoing>(settings.send_queue_size);
+                server
+                    .message_to_be_sent_to_client_over_ws
+                    .lock()
+                    .unwrap()
+                    .insert(client_id, send_ws.clone());
+                let (mut send, mut receive) = client.connection.split();
+                let mut force_close = server.force_close_receiver.clone();
+                let connections = server.connections.clone();
+                connections.fetch_add(1, Ordering::SeqCst);
+                let last_seen = Arc::new(Mutex::new(Instant::now()));
+                let receive_last_seen = last_seen.clone();
+                let log_payloads = settings.log_payloads;
+                let max_message_size = settings.max_message_size;
+                let mut rate_limit = settings.rate_limit.map(TokenBucket::new);
+                let max_violations = settings.max_violations;
+                let kicked = server.kicked.clone();
+                let traffic = server.clients[&client_id].traffic.clone();
+                let receive_traffic = traffic.clone();
                 // task to listen to ws messages from client
                 task_pool
                     .spawn(Compat::new(async move {
-                        while *running.lock().expect("lock") {
-                            match receive.next().await {
-                                None => trace!("ws next is none"),
-                                Some(msg) => match msg {
-                                    Ok(msg) => match msg {
-                                        Message::Text(msg) => {
-                                            info!("server received {:?}", msg);
+                        ws_to_event_channel_sender
+                            .send(opened)
+                            .await
+                            .unwrap_or_else(|e| warn!("Failed to report open: {:?}", e));
+                        let mut violations = 0;
+                        loop {
+                            let next_incoming_message = receive.next().fuse();
+                            let next_force_close = force_close.changed().fuse();
+                            futures::pin_mut!(next_incoming_message, next_force_close);
+                            let msg = futures::select! {
+                                msg = next_incoming_message => msg,
+                                _ = next_force_close => {
+                                    warn!("Dropping client {} that didn't close in time", client_id);
+                                    break;
+                                }
+                            };
+                            match msg {
+                                None => {
+                                    trace!("ws next is none");
+                                    break;
+                                }
+                                Some(msg) => {
+                                    *receive_last_seen.lock().expect("lock") = Instant::now();
+                                    let exceeded = match &msg {
+                                        Ok(msg @ (Message::Text(_) | Message::Binary(_))) => {
+                                            receive_traffic.received(msg.len());
+                                            exceeded_limit(msg, max_message_size, &mut rate_limit)
+                                        }
+                                        // tungstenite refuses messages over the configured size
+                                        Err(WsError::Capacity(_)) => Some(Limit::MessageSize),
+                                        _ => None,
+                                    };
+                                    if let Some(limit) = exceeded {
+                                        receive_traffic.dropped();
+                                        ws_to_event_channel_sender
+                                            .send(WebsocketServerEvent::LimitExceeded(client_id, limit))
+                                            .await
+                                            .unwrap_or_else(|e| warn!("Failed to report limit: {:?}", e));
+                                        violations += 1;
+                                        if max_violations.is_some_and(|max| violations >= max) {
+                                            warn!("Disconnecting client {} for going over its limits", client_id);
+                                            kicked.lock().expect("lock").insert(client_id);
+                                            send_ws
+                                                .send(Outgoing::Close(CloseFrame {
+                                                    code: CloseCode::Policy,
+                                                    reason: "Too many messages".into(),
+                                                }))
+                                                .await
+                                                .unwrap_or_else(|e| warn!("Failed to close: {:?}", e));
+                                            break;
+                                        }
+                                        if msg.is_ok() {
+                                            continue;
+                                        }
+                                    }
+                                    match msg {
+                                        Ok(Message::Text(msg)) => {
+                                            if log_payloads {
+                                                info!("server received {:?}", msg);
+                                            }
                                             ws_to_event_channel_sender
                                                 .send(WebsocketServerEvent::OnMessage(
-                                                    client_id,
-                                                    msg.clone(),
+                                                    client_id, msg,
                                                 ))
                                                 .await
                                                 .unwrap();
-                                            info!("done sending {:?}", msg);
                                         }
-
-                                        o => info!("Server loop recv: {:?}", o),
-                                    },
-                                    Err(e) => {
-                                        info!("websocket client error: {:?}", e);
-                                        break;
+                                        Ok(o) => trace!("Server loop recv: {:?}", o),
+                                        Err(e) => {
+                                            info!("websocket client error: {:?}", e);
+                                            break;
+                                        }
                                     }
-                                },
+                                }
                             }
                         }
+                        ws_to_event_channel_sender
+                            .send(WebsocketServerEvent::OnClose(client_id))
+                            .await
+                            .unwrap_or_else(|e| warn!("Failed to report close: {:?}", e));
+                        connections.fetch_sub(1, Ordering::SeqCst);
                         warn!("ws to event loop closing")
                     }))
                     .detach();
 
-                let (send_ws, mut message_to_send) =
-                    tokio::sync::mpsc::channel::<WebsocketClientEvent>(10);
-                server
-                    .message_to_be_sent_to_client_over_ws
-                    .lock()
-                    .unwrap()
-                    .push(send_ws);
-                let running = server.run_listen_loop.clone();
+                let heartbeat_interval = settings.heartbeat_interval;
+                let heartbeat_timeout = settings.heartbeat_timeout;
                 // One loop per client, takes messages and sends them over websocket
                 task_pool
                     .spawn(Compat::new(async move {
                         info!("Setting up send over ws loop");
-                        send.send(Message::Text(format!("##CLIENT_ID## {}", client_id)))
-                            .await
-                            .unwrap_or_else(|e| warn!("Initial failed:{:?}", e));
-                        while *running.lock().expect("lock") {
-                            match message_to_send.recv().await {
-                                Some(WebsocketClientEvent::OnMessage(msg)) => {
-                                    info!("Sending {:?}", msg);
-                                    send.send(Message::Text(msg))
+                        let bytes = welcome.len();
+                        match send.send(Message::Text(welcome)).await {
+                            Ok(()) => traffic.sent(bytes),
+                            Err(e) => warn!("Initial failed:{:?}", e),
+                        }
+                        let mut heartbeat = heartbeat_interval.map(|period| {
+                            tokio::time::interval_at(
+                                tokio::time::Instant::now() + period,
+                                period,
+                            )
+                        });
+                        'main: loop {
+                            let next_message_to_send = message_to_send.recv().fuse();
+                            let next_heartbeat = next_heartbeat(&mut heartbeat).fuse();
+                            futures::pin_mut!(next_message_to_send, next_heartbeat);
+                            futures::select! {
+                                msg = next_message_to_send => match msg {
+                                    Some(Outgoing::Message(msg)) => {
+                                        if log_payloads {
+                                            info!("Sending {:?}", msg);
+                                        }
+                                        let bytes = msg.len();
+                                        match send.send(Message::Text(msg)).await {
+                                            Ok(()) => traffic.sent(bytes),
+                                            Err(e) => {
+                                                warn!("Send failed:{:?}", e);
+                                                traffic.dropped();
+                                            }
+                                        }
+                                    }
+                                    None => {
+                                        warn!("channel for msg to send to ws returned none");
+                                        break 'main;
+                                    }
+                                    Some(Outgoing::Close(frame)) => {
+                                        info!("Closing connection to client {}: {}", client_id, frame.reason);
+                                        send.send(Message::Close(Some(frame)))
                                         .await
-                                        .unwrap_or_else(|e| warn!("Send failed:{:?}", e));
-                                }
-                                None => {
-                                    warn!("channel for msg to send to ws returned none");
-                                    break;
+                                        .unwrap_or_else(|e| warn!("Close failed:{:?}", e));
+                                        break 'main;
+                                    }
+                                },
+                                _ = next_heartbeat => {
+                                    if last_seen.lock().expect("lock").elapsed() > heartbeat_timeout {
+                                        warn!("Client {} stopped responding, closing", client_id);
+                                        send.close()
+                                            .await
+                                            .unwrap_or_else(|e| warn!("Close failed:{:?}", e));
+                                        break 'main;
+                                    }
+                                    send.send(Message::Ping(vec![]))
+                                        .await
+                                        .unwrap_or_else(|e| warn!("Ping failed:{:?}", e));
                                 }
-                                Some(WebsocketClientEvent::OnOpen(_client_id)) => {}
-                                Some(WebsocketClientEvent::OnClose) => warn!("ws close"),
                             }
                         }
                         warn!("channel to ws loop closing");
@@ -175,24 +434,185 @@ fn websocket_server_system(
                     .detach();
             }
         }
+        WsServerState::ShuttingDown(timeout) => {
+            info!("Shutting down, closing all connections");
+            *server.run_listen_loop.lock().unwrap() = false;
+            for sender in server
+                .message_to_be_sent_to_client_over_ws
+                .lock()
+                .unwrap()
+                .values()
+            {
+                sender
+                    .try_send(Outgoing::Close(CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "Server shutting down".into(),
+                    }))
+                    .unwrap_or_else(|e| warn!("Failed to send close:{:?}", e));
+            }
+            let timeout = timeout.unwrap_or(settings.shutdown_timeout);
+            server.state = WsServerState::Draining(Instant::now() + timeout);
+        }
+        WsServerState::Draining(deadline) => {
+            if server.connections.load(Ordering::SeqCst) > 0 || server.is_listening() {
+                if Instant::now() >= deadline {
+                    server
+                        .force_close
+                        .send(true)
+                        .unwrap_or_else(|e| warn!("Failed to force close:{:?}", e));
+                }
+                return;
+            }
+            // pass on whatever the clients said on their way out before announcing the stop
+            for receiver in server
+                .ws_to_event_channel_receiver
+                .lock()
+                .unwrap()
+                .iter_mut()
+            {
+                while let Ok(msg) = receiver.try_recv() {
+                    event_writer.send(msg);
+                }
+            }
+            server.ws_to_event_channel_receiver.lock().unwrap().clear();
+            server
+                .message_to_be_sent_to_client_over_ws
+                .lock()
+                .unwrap()
+                .clear();
+            // nobody can resume once the server is gone
+            let suspended: Vec<u64> = server.suspended.drain().map(|(id, _)| id).collect();
+            for client_id in suspended {
+                event_writer.send(WebsocketServerEvent::OnClose(client_id));
+                server.closed_clients.push(client_id);
+            }
+            info!("Server stopped");
+            event_writer.send(WebsocketServerEvent::ServerStopped);
+            server.state = match server.listen_address {
+                Some(_) => WsServerState::Starting,
+                None => WsServerState::Stopped,
+            };
+        }
+        WsServerState::Stopped => {}
     }
     trace!("End of ws system");
 }
 
+// tells a client over the limit the server is full and hangs up once it has answered
+fn reject_full(mut connection: Connection, task_pool: &IoTaskPool, timeout: Duration) {
+    task_pool
+        .spawn(Compat::new(async move {
+            connection
+                .send(Message::Close(Some(CloseFrame {
+                    code: CloseCode::Again,
+                    reason: SERVER_FULL.into(),
+                })))
+                .await
+                .unwrap_or_else(|e| warn!("Close failed:{:?}", e));
+            let answered = async { while let Some(Ok(_)) = connection.next().await {} }.fuse();
+            let timed_out = async {
+                async_io::Timer::after(timeout).await;
+            }
+            .fuse();
+            futures::pin_mut!(answered, timed_out);
+            futures::select! {
+                _ = answered => {},
+                _ = timed_out => {},
+            }
+        }))
+        .detach();
+}
+
+// which limit `msg` goes over, if any. Takes a token from the bucket when it doesn't
+fn exceeded_limit(
+    msg: &Message,
+    max_message_size: Option<usize>,
+    rate_limit: &mut Option<TokenBucket>,
+) -> Option<Limit> {
+    if max_message_size.is_some_and(|max| msg.len() > max) {
+        return Some(Limit::MessageSize);
+    }
+    match rate_limit.as_mut().map(TokenBucket::take) {
+        Some(false) => Some(Limit::Rate),
+        _ => None,
+    }
+}
+
+// what a client's send loop is asked to do
+#[derive(Debug)]
+pub(crate) enum Outgoing {
+    Message(String),
+    Close(CloseFrame<'static>),
+}
+
+async fn next_heartbeat(heartbeat: &mut Option<tokio::time::Interval>) {
+    match heartbeat {
+        Some(heartbeat) => {
+            heartbeat.tick().await;
+        }
+        None => futures::future::pending().await,
+    }
+}
+
 // use by the server to talk to clients
 pub struct WebsocketServerResource {
     state: WsServerState,
     listen_address: Option<String>,
     pub ws_to_event_channel_receiver: Mutex<Vec<tokio::sync::mpsc::Receiver<WebsocketServerEvent>>>,
-    pub new_clients_event_stream: EventStream<WebSocketStream<TcpStream>>,
-    pub message_to_be_sent_to_client_over_ws:
-        Mutex<Vec<tokio::sync::mpsc::Sender<WebsocketClientEvent>>>,
+    pub new_clients_event_stream: EventStream<IncomingConnection>,
+    pub(crate) message_to_be_sent_to_client_over_ws:
+        Mutex<HashMap<u64, tokio::sync::mpsc::Sender<Outgoing>>>,
     run_listen_loop: Arc<Mutex<bool>>,
-    next_client_id: u64,
+    listening: Arc<AtomicBool>,
+    bound_address: Arc<Mutex<Option<SocketAddr>>>,
+    connections: Arc<AtomicUsize>,
+    force_close: tokio::sync::watch::Sender<bool>,
+    force_close_receiver: tokio::sync::watch::Receiver<bool>,
+    transport: Arc<dyn Transport>,
+    authenticator: Option<Authenticator>,
+    clients: HashMap<u64, ClientInfo>,
+    closed_clients: Vec<u64>,
+    // disconnected clients that may still resume, with when they're closed for good
+    suspended: HashMap<u64, Instant>,
+    // clients the server hung up on, they don't get to resume
+    kicked: Arc<Mutex<HashSet<u64>>>,
+    max_clients: Option<usize>,
+    overflow: Overflow,
+    // named sets of clients to broadcast to, without empty ones
+    groups: HashMap<String, HashSet<u64>>,
+    // every connection since the server started
+    traffic: Arc<TrafficCounters>,
+    send_queue_size: usize,
+}
+
+/// The close reason clients turned away by [`Overflow::Reject`] get
+pub const SERVER_FULL: &str = "Server full";
+
+/// What happens to clients connecting while the server is at
+/// [`WebsocketServerResource::set_max_clients`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Closed right away with a [`SERVER_FULL`] close frame
+    #[default]
+    Reject,
+    /// Let in as spectators, see [`ClientInfo::spectator`]
+    Spectate,
+}
+
+/// How many clients the server has, updated every frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ConnectionCount {
+    /// Open connections, spectators included
+    pub connections: usize,
+    /// Clients counting towards [`WebsocketServerResource::set_max_clients`],
+    /// including suspended ones waiting to resume
+    pub players: usize,
+    pub spectators: usize,
 }
 
 impl Default for WebsocketServerResource {
     fn default() -> Self {
+        let (force_close, force_close_receiver) = tokio::sync::watch::channel(false);
         Self {
             state: WsServerState::WaitingToStart,
             listen_address: None,
@@ -200,16 +620,36 @@ impl Default for WebsocketServerResource {
             new_clients_event_stream: Default::default(),
             message_to_be_sent_to_client_over_ws: Default::default(),
             run_listen_loop: Arc::new(Mutex::new(true)),
-            next_client_id: 0,
+            listening: Default::default(),
+            bound_address: Default::default(),
+            connections: Default::default(),
+            force_close,
+            force_close_receiver,
+            transport: Arc::new(TcpTransport::default()),
+            authenticator: None,
+            clients: Default::default(),
+            closed_clients: vec![],
+            suspended: Default::default(),
+            kicked: Default::default(),
+            max_clients: None,
+            overflow: Overflow::default(),
+            groups: Default::default(),
+            traffic: Default::default(),
+            send_queue_size: 0,
         }
     }
 }
 
 impl WebsocketServerResource {
+    /// A random id that isn't used by any current client, so ids can't be guessed
+    /// and don't repeat after a restart
     pub fn generate_next_client_id(&mut self) -> u64 {
-        let id = self.next_client_id;
-        self.next_client_id += 1;
-        id
+        loop {
+            let id = rand::random();
+            if !self.clients.contains_key(&id) {
+                return id;
+            }
+        }
     }
 }
 
@@ -218,27 +658,263 @@ enum WsServerState {
     WaitingToStart,
     Starting,
     Connected,
+    // waiting for the system to send close frames, `None` means use the configured timeout
+    ShuttingDown(Option<Duration>),
+    // waiting for clients to acknowledge the close until the deadline
+    Draining(Instant),
+    Stopped,
 }
 
 impl WebsocketServerResource {
+    /// Starts listening on `address`. If the server is already running it is shut down
+    /// like with [`Self::close`] first and then started again on the new address
     pub fn listen(&mut self, address: &str) {
         info!("Trying to listen on {}", address);
         self.listen_address = Some(address.to_string()); // todo use String
-        self.state = WsServerState::Starting;
+        match self.state {
+            WsServerState::WaitingToStart | WsServerState::Stopped => {
+                self.state = WsServerState::Starting
+            }
+            WsServerState::Connected => self.state = WsServerState::ShuttingDown(None),
+            // already on the way, the new address is picked up when it gets there
+            WsServerState::Starting
+            | WsServerState::ShuttingDown(_)
+            | WsServerState::Draining(_) => {}
+        }
+    }
+    /// The address the server is accepting connections on, `None` while not listening
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        *self.bound_address.lock().unwrap()
+    }
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::SeqCst)
+    }
+    /// Listening and letting clients in, `false` while starting up or shutting down
+    pub fn is_accepting(&self) -> bool {
+        matches!(self.state, WsServerState::Connected) && self.is_listening()
+    }
+    /// Replaces how connections are accepted, [`TcpTransport`] by default.
+    /// Takes effect the next time the server starts listening
+    pub fn set_transport(&mut self, transport: impl Transport) {
+        self.transport = Arc::new(transport);
+    }
+    /// Checks every upgrade request with `authenticator` before letting it connect.
+    /// Takes effect the next time the server starts listening
+    pub fn set_authenticator(
+        &mut self,
+        authenticator: impl Fn(&UpgradeRequest) -> Result<Identity, Rejection> + Send + Sync + 'static,
+    ) {
+        self.authenticator = Some(Arc::new(authenticator));
+    }
+    /// Who `client_id` authenticated as, `None` without an authenticator.
+    /// Still available in the frame after its [`WebsocketServerEvent::OnClose`]
+    pub fn identity(&self, client_id: u64) -> Option<&Identity> {
+        self.client(client_id)?.identity.as_ref()
+    }
+    /// Everything known about `client_id`.
+    /// Still available in the frame after its [`WebsocketServerEvent::OnClose`]
+    pub fn client(&self, client_id: u64) -> Option<&ClientInfo> {
+        self.clients.get(&client_id)
+    }
+    /// Used to attach user data to a client, see [`ClientInfo::insert`]
+    pub fn client_mut(&mut self, client_id: u64) -> Option<&mut ClientInfo> {
+        self.clients.get_mut(&client_id)
+    }
+    /// The client that was handed `session_token`
+    pub fn client_by_token(&self, session_token: &str) -> Option<u64> {
+        self.clients
+            .iter()
+            .find(|(_, client)| client.session_token().as_str() == session_token)
+            .map(|(client_id, _)| *client_id)
+    }
+    /// Caps how many clients the server takes, `None` means no cap. Clients resuming their
+    /// session always get back in, `overflow` decides what happens to new ones over the cap.
+    /// Spectators don't count, unlike [`WebsocketServerSettings::max_connections`] which is a
+    /// hard limit on open sockets
+    pub fn set_max_clients(&mut self, max_clients: Option<usize>, overflow: Overflow) {
+        self.max_clients = max_clients;
+        self.overflow = overflow;
+    }
+    pub fn is_spectator(&self, client_id: u64) -> bool {
+        self.client(client_id)
+            .is_some_and(|client| client.spectator)
+    }
+    // clients that haven't closed, suspended ones included
+    fn current_clients(&self) -> impl Iterator<Item = (&u64, &ClientInfo)> {
+        self.clients
+            .iter()
+            .filter(|(client_id, _)| !self.closed_clients.contains(client_id))
+    }
+    fn is_full(&self) -> bool {
+        let players = self
+            .current_clients()
+            .filter(|(_, client)| !client.spectator)
+            .count();
+        self.max_clients.is_some_and(|max| players >= max)
+    }
+    /// Whether `client_id` is disconnected and waiting to resume its session
+    pub fn is_suspended(&self, client_id: u64) -> bool {
+        self.suspended.contains_key(&client_id)
+    }
+    // the suspended client holding `session_token`, if it's the same identity coming back
+    fn resumable_client(&self, session_token: &str, identity: &Option<Identity>) -> Option<u64> {
+        let client_id = self.client_by_token(session_token)?;
+        let same_identity = self.clients[&client_id].identity == *identity;
+        (self.is_suspended(client_id) && same_identity).then_some(client_id)
+    }
+    /// Every client including the suspended ones
+    pub fn clients(&self) -> impl Iterator<Item = (u64, &ClientInfo)> {
+        self.clients
+            .iter()
+            .map(|(client_id, client)| (*client_id, client))
+    }
+    /// Serves `wss://` using a PEM encoded certificate chain and private key,
+    /// see [`TcpTransport::with_tls`]
+    pub fn use_tls(
+        &mut self,
+        certificate: impl AsRef<Path>,
+        private_key: impl AsRef<Path>,
+    ) -> Result<(), TlsError> {
+        self.set_transport(TcpTransport::with_tls(certificate, private_key)?);
+        Ok(())
     }
     pub fn broadcast(&mut self, message: String) {
         let senders = self.message_to_be_sent_to_client_over_ws.lock().unwrap();
-        for sender in &*senders {
-            sender
-                .blocking_send(WebsocketClientEvent::OnMessage(message.clone()))
-                .unwrap_or_else(|e| warn!("Broadcast failed:{:?}", e));
+        for (client_id, sender) in senders.iter() {
+            if let Err(e) = sender.blocking_send(Outgoing::Message(message.clone())) {
+                warn!("Broadcast failed:{:?}", e);
+                self.count_dropped(*client_id);
+            }
+        }
+    }
+    /// Adds `client_id` to `group`, which exists while it has clients. False if it was
+    /// already in it or there's no such client. Clients stay in their groups while they're
+    /// suspended and leave all of them the frame after their [`WebsocketServerEvent::OnClose`]
+    pub fn join_group(&mut self, group: &str, client_id: u64) -> bool {
+        if !self.clients.contains_key(&client_id) {
+            return false;
+        }
+        self.groups
+            .entry(group.to_string())
+            .or_default()
+            .insert(client_id)
+    }
+    /// Takes `client_id` out of `group`, false if it wasn't in it
+    pub fn leave_group(&mut self, group: &str, client_id: u64) -> bool {
+        let members = match self.groups.get_mut(group) {
+            Some(members) => members,
+            None => return false,
+        };
+        let left = members.remove(&client_id);
+        if members.is_empty() {
+            self.groups.remove(group);
+        }
+        left
+    }
+    fn leave_all_groups(&mut self, client_id: u64) {
+        self.groups.retain(|_, members| {
+            members.remove(&client_id);
+            !members.is_empty()
+        });
+    }
+    /// The clients in `group`, suspended ones included
+    pub fn group_members(&self, group: &str) -> impl Iterator<Item = u64> + '_ {
+        self.groups.get(group).into_iter().flatten().copied()
+    }
+    /// The groups `client_id` is in
+    pub fn groups_of(&self, client_id: u64) -> impl Iterator<Item = &str> {
+        self.groups
+            .iter()
+            .filter(move |(_, members)| members.contains(&client_id))
+            .map(|(group, _)| group.as_str())
+    }
+    /// Sends `message` to every connected client in `group`, suspended ones miss it
+    pub fn broadcast_to_group(&self, group: &str, message: String) {
+        let senders = self.message_to_be_sent_to_client_over_ws.lock().unwrap();
+        for client_id in self.group_members(group) {
+            let sender = match senders.get(&client_id) {
+                Some(sender) => sender,
+                None => continue,
+            };
+            if let Err(e) = sender.blocking_send(Outgoing::Message(message.clone())) {
+                warn!("Broadcast to {} failed:{:?}", group, e);
+                self.count_dropped(client_id);
+            }
         }
     }
+    /// Sends `message` to `client_id` only
+    pub fn send(&self, client_id: u64, message: String) {
+        let senders = self.message_to_be_sent_to_client_over_ws.lock().unwrap();
+        match senders.get(&client_id) {
+            Some(sender) => {
+                if let Err(e) = sender.blocking_send(Outgoing::Message(message)) {
+                    warn!("Send to {} failed:{:?}", client_id, e);
+                    self.count_dropped(client_id);
+                }
+            }
+            None => warn!("No client {} to send to", client_id),
+        }
+    }
+    fn count_dropped(&self, client_id: u64) {
+        match self.clients.get(&client_id) {
+            Some(client) => client.traffic.dropped(),
+            None => self.traffic.dropped(),
+        }
+    }
+    /// What went over `client_id`'s connection, across resumed sessions
+    pub fn traffic(&self, client_id: u64) -> Option<TrafficStats> {
+        let client = self.clients.get(&client_id)?;
+        Some(client.traffic.stats(self.queued(client_id)))
+    }
+    /// What went over every connection since the server started listening
+    pub fn total_traffic(&self) -> TrafficStats {
+        let queued = self.clients.keys().map(|id| self.queued(*id)).sum();
+        self.traffic.stats(queued)
+    }
+    // messages waiting in `client_id`'s send queue
+    fn queued(&self, client_id: u64) -> usize {
+        let senders = self.message_to_be_sent_to_client_over_ws.lock().unwrap();
+        senders.get(&client_id).map_or(0, |sender| {
+            self.send_queue_size.saturating_sub(sender.capacity())
+        })
+    }
+    /// Closes the connection to `client_id` with a policy violation close frame, the client
+    /// can't resume its session afterwards
+    pub fn disconnect(&self, client_id: u64, reason: &str) {
+        let senders = self.message_to_be_sent_to_client_over_ws.lock().unwrap();
+        let sender = match senders.get(&client_id) {
+            Some(sender) => sender,
+            None => return warn!("No client {} to disconnect", client_id),
+        };
+        self.kicked.lock().unwrap().insert(client_id);
+        sender
+            .try_send(Outgoing::Close(CloseFrame {
+                code: CloseCode::Policy,
+                reason: reason.to_string().into(),
+            }))
+            .unwrap_or_else(|e| warn!("Failed to send close:{:?}", e));
+    }
+    /// Gracefully stops the server using the configured
+    /// [`WebsocketServerSettings::shutdown_timeout`], see [`Self::shutdown`]
     pub fn close(&mut self) {
-        self.message_to_be_sent_to_client_over_ws
-            .lock()
-            .unwrap()
-            .clear();
-        *self.run_listen_loop.lock().unwrap() = false;
+        self.stop(None);
+    }
+    /// Stops accepting connections and sends every client a going away close frame.
+    /// Clients that haven't closed their end within `timeout` are dropped, after which
+    /// [`WebsocketServerEvent::ServerStopped`] is sent
+    pub fn shutdown(&mut self, timeout: Duration) {
+        self.stop(Some(timeout));
+    }
+    fn stop(&mut self, timeout: Option<Duration>) {
+        self.listen_address = None;
+        match self.state {
+            WsServerState::Starting | WsServerState::Connected => {
+                self.state = WsServerState::ShuttingDown(timeout)
+            }
+            WsServerState::WaitingToStart
+            | WsServerState::ShuttingDown(_)
+            | WsServerState::Draining(_)
+            | WsServerState::Stopped => {}
+        }
     }
 }
@@ -0,0 +1,153 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[cfg(feature = "native")]
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+/// Settings for [`crate::WebsocketServerPlugin`], insert it as a resource before adding the plugin
+/// to override the defaults
+#[derive(Debug, Clone)]
+pub struct WebsocketServerSettings {
+    /// How many received messages per client can wait to be turned into events
+    pub event_queue_size: usize,
+    /// How many outgoing messages per client can wait to be written to the socket
+    pub send_queue_size: usize,
+    /// Largest message in bytes a client may send, `None` means no limit. Larger messages are
+    /// dropped and reported with [`crate::WebsocketServerEvent::LimitExceeded`]
+    pub max_message_size: Option<usize>,
+    /// How many messages each client may send, messages over the limit are dropped and
+    /// reported with [`crate::WebsocketServerEvent::LimitExceeded`]. `None` means no limit
+    pub rate_limit: Option<RateLimit>,
+    /// Clients that go over a limit this many times are disconnected, `None` never disconnects them
+    pub max_violations: Option<u32>,
+    /// Websocket connections over this limit are dropped without a word right after being
+    /// accepted, `None` means no limit. It counts every open socket, spectators and closing ones
+    /// included, while [`crate::WebsocketServerResource::set_max_clients`] decides how many play
+    /// and tells the rest. Plain HTTP requests for [`Self::static_files`] never count
+    pub max_connections: Option<usize>,
+    /// How often to ping clients, `None` disables the heartbeat
+    pub heartbeat_interval: Option<Duration>,
+    /// Clients that have been silent for this long are disconnected, only used with a heartbeat
+    pub heartbeat_timeout: Duration,
+    /// How long a new connection has to complete the websocket upgrade
+    pub handshake_timeout: Duration,
+    /// How long [`crate::WebsocketServerResource::close`] waits for clients to acknowledge
+    /// the close frame before dropping them
+    pub shutdown_timeout: Duration,
+    /// Log the content of every message sent and received at info level
+    pub log_payloads: bool,
+    /// Proxies in front of the server, connections from them are attributed to the address
+    /// in their `X-Forwarded-For` header instead
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Web pages allowed to connect, like `https://example.com`. Upgrade requests with any other
+    /// `Origin` are refused with 403, ones without an `Origin` come from outside a browser and
+    /// are let through. Empty allows every origin
+    pub allowed_origins: Vec<String>,
+    /// How often to send [`crate::ServerDiagnostics`], `None` never sends them
+    pub diagnostics_interval: Option<Duration>,
+    /// How long a disconnected client keeps its id and user data while it may reconnect with its
+    /// session token, zero closes clients as soon as their connection drops
+    pub session_grace_period: Duration,
+    /// Also serve files over plain HTTP on the websocket port, websockets are then only
+    /// accepted on [`StaticFiles::websocket_path`]. Only used by [`crate::TcpTransport`]
+    pub static_files: Option<StaticFiles>,
+}
+
+impl Default for WebsocketServerSettings {
+    fn default() -> Self {
+        Self {
+            event_queue_size: 100,
+            send_queue_size: 10,
+            max_message_size: Some(64 << 20),
+            rate_limit: None,
+            max_violations: None,
+            max_connections: None,
+            heartbeat_interval: Some(Duration::from_secs(5)),
+            heartbeat_timeout: Duration::from_secs(15),
+            handshake_timeout: Duration::from_secs(10),
+            shutdown_timeout: Duration::from_secs(5),
+            log_payloads: false,
+            trusted_proxies: vec![],
+            allowed_origins: vec![],
+            session_grace_period: Duration::ZERO,
+            diagnostics_interval: None,
+            static_files: None,
+        }
+    }
+}
+
+/// See [`WebsocketServerSettings::static_files`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticFiles {
+    /// Directory the files are served from, `/` is its `index.html`
+    pub root: PathBuf,
+    /// Where upgrade requests go, like `/ws`
+    pub websocket_path: String,
+}
+
+/// A token bucket, see [`WebsocketServerSettings::rate_limit`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// How many messages per second a client may send on average
+    pub per_second: f64,
+    /// How many messages a client that has been quiet may send at once
+    pub burst: u32,
+}
+
+#[cfg(feature = "native")]
+impl WebsocketServerSettings {
+    pub(crate) fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_message_size: self.max_message_size,
+            max_frame_size: self.max_message_size,
+            ..Default::default()
+        }
+    }
+}
+
+/// Settings for [`crate::WebsocketPlugin`], insert it as a resource before adding the plugin
+/// to override the defaults
+#[derive(Debug, Clone)]
+pub struct WebsocketSettings {
+    /// How many received messages can wait to be turned into events, ignored on web
+    pub event_queue_size: usize,
+    /// How many outgoing messages can wait to be written to the socket, ignored on web
+    pub send_queue_size: usize,
+    /// Largest message in bytes the server may send, `None` means no limit
+    pub max_message_size: Option<usize>,
+    /// How often to ping the server, `None` disables the heartbeat.
+    /// Ignored on web where the browser answers the server's pings
+    pub heartbeat_interval: Option<Duration>,
+    /// How long connecting and upgrading to a websocket may take
+    pub handshake_timeout: Duration,
+    /// Log the content of every message sent and received at info level
+    pub log_payloads: bool,
+    /// How often to send [`crate::ClientDiagnostics`], `None` never sends them
+    pub diagnostics_interval: Option<Duration>,
+}
+
+impl Default for WebsocketSettings {
+    fn default() -> Self {
+        Self {
+            event_queue_size: 10,
+            send_queue_size: 10,
+            max_message_size: Some(64 << 20),
+            heartbeat_interval: Some(Duration::from_secs(5)),
+            handshake_timeout: Duration::from_secs(10),
+            log_payloads: false,
+            diagnostics_interval: None,
+        }
+    }
+}
+
+#[cfg(feature = "native")]
+impl WebsocketSettings {
+    pub(crate) fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_message_size: self.max_message_size,
+            max_frame_size: self.max_message_size,
+            ..Default::default()
+        }
+    }
+}
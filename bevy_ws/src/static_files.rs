@@ -0,0 +1,211 @@
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::server::SERVER_FULL;
+use crate::settings::StaticFiles;
+
+// browsers send far less than this before the body
+const MAX_HEAD_SIZE: usize = 16 << 10;
+
+/// The request line and headers of an HTTP request, read to decide if it's a websocket upgrade
+pub(crate) struct RequestHead {
+    bytes: Vec<u8>,
+    method: String,
+    pub(crate) path: String,
+    upgrade: bool,
+}
+
+impl RequestHead {
+    pub(crate) fn is_upgrade_to(&self, path: &str) -> bool {
+        self.upgrade && self.path == path
+    }
+}
+
+pub(crate) async fn read_head<S>(stream: &mut S) -> io::Result<RequestHead>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut bytes = vec![];
+    let mut chunk = [0; 1024];
+    while !bytes.windows(4).any(|window| window == b"\r\n\r\n") {
+        if bytes.len() > MAX_HEAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request head too large",
+            ));
+        }
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        bytes.extend_from_slice(&chunk[..read]);
+    }
+    let text = String::from_utf8_lossy(&bytes).into_owned();
+    let mut lines = text.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line.next().unwrap_or_default();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    let upgrade = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .any(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("upgrade")
+                && value.trim().eq_ignore_ascii_case("websocket")
+        });
+    Ok(RequestHead {
+        bytes,
+        method,
+        path,
+        upgrade,
+    })
+}
+
+/// Answers a request for one of the files with the file or an error status, then closes
+/// the connection
+pub(crate) async fn serve<S>(
+    mut stream: S,
+    files: &StaticFiles,
+    head: &RequestHead,
+) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let (status, content_type, body) = match head.method.as_str() {
+        // websockets only live on the websocket path
+        _ if head.upgrade => ("404 Not Found", "text/plain", vec![]),
+        "GET" | "HEAD" => match file_path(&files.root, &head.path) {
+            Some(path) => match tokio::fs::read(&path).await {
+                Ok(body) => ("200 OK", content_type(&path), body),
+                Err(_) => ("404 Not Found", "text/plain", vec![]),
+            },
+            None => ("404 Not Found", "text/plain", vec![]),
+        },
+        _ => ("405 Method Not Allowed", "text/plain", vec![]),
+    };
+    let send_body = head.method != "HEAD";
+    respond(&mut stream, status, content_type, &body, send_body).await
+}
+
+/// Turns away a websocket upgrade when the server is at its max connections, then closes
+/// the connection
+pub(crate) async fn refuse_full<S>(mut stream: S) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let body = SERVER_FULL.as_bytes();
+    respond(
+        &mut stream,
+        "503 Service Unavailable",
+        "text/plain",
+        body,
+        true,
+    )
+    .await
+}
+
+// writes the whole response, without the body when only its head was asked for
+async fn respond<S>(
+    stream: &mut S,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+    send_body: bool,
+) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    if send_body {
+        stream.write_all(body).await?;
+    }
+    stream.shutdown().await
+}
+
+// where `path` is under `root`, `None` for anything that would leave it
+fn file_path(root: &Path, path: &str) -> Option<PathBuf> {
+    let relative = match path.trim_start_matches('/') {
+        "" => "index.html",
+        relative => relative,
+    };
+    let relative = Path::new(relative);
+    relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+        .then(|| root.join(relative))
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript",
+        Some("wasm") => "application/wasm",
+        Some("css") => "text/css",
+        Some("json") => "application/json",
+        Some("png") => "image/png",
+        Some("ogg") => "audio/ogg",
+        Some("ttf") => "font/ttf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Hands out the already read request head again before reading on from the stream,
+/// so the websocket handshake sees the whole request
+pub(crate) struct Replay<S> {
+    head: Vec<u8>,
+    replayed: usize,
+    stream: S,
+}
+
+impl<S> Replay<S> {
+    pub(crate) fn new(head: Option<RequestHead>, stream: S) -> Self {
+        Self {
+            head: head.map(|head| head.bytes).unwrap_or_default(),
+            replayed: 0,
+            stream,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Replay<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.replayed < this.head.len() {
+            let end = this.head.len().min(this.replayed + buf.remaining());
+            buf.put_slice(&this.head[this.replayed..end]);
+            this.replayed = end;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Replay<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
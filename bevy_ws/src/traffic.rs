@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Message and byte counts of a connection, or of all connections together
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+    /// Messages thrown away instead of delivered, for going over a limit or
+    /// because the connection was gone
+    pub dropped: u64,
+    /// Messages waiting to be written to the socket right now
+    pub queued: usize,
+}
+
+/// Sent by the server every [`crate::WebsocketServerSettings::diagnostics_interval`]
+#[derive(Debug, Clone)]
+pub struct ServerDiagnostics {
+    /// Everything since the server started, closed connections included
+    pub total: TrafficStats,
+    /// The connected clients
+    pub clients: HashMap<u64, TrafficStats>,
+}
+
+/// Sent by the client every [`crate::WebsocketSettings::diagnostics_interval`],
+/// everything since the client started
+#[derive(Debug, Clone)]
+pub struct ClientDiagnostics(pub TrafficStats);
+
+// updated by the connection tasks, read whenever someone asks for stats
+#[derive(Debug, Default)]
+pub(crate) struct TrafficCounters {
+    messages_in: AtomicU64,
+    bytes_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_out: AtomicU64,
+    dropped: AtomicU64,
+    // the server wide counters a connection's counts also go to
+    total: Option<Arc<TrafficCounters>>,
+}
+
+impl TrafficCounters {
+    #[cfg(feature = "native")]
+    pub(crate) fn counting_towards(total: Arc<TrafficCounters>) -> Self {
+        Self {
+            total: Some(total),
+            ..Default::default()
+        }
+    }
+    pub(crate) fn received(&self, bytes: usize) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(total) = &self.total {
+            total.received(bytes);
+        }
+    }
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(total) = &self.total {
+            total.sent(bytes);
+        }
+    }
+    pub(crate) fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        if let Some(total) = &self.total {
+            total.dropped();
+        }
+    }
+    pub(crate) fn stats(&self, queued: usize) -> TrafficStats {
+        TrafficStats {
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            queued,
+        }
+    }
+}
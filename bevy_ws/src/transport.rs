@@ -0,0 +1,638 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_compat::Compat;
+use bevy::tasks::IoTaskPool;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::future::BoxFuture;
+use futures::{FutureExt, Sink, Stream};
+use log::info;
+use log::trace;
+use log::warn;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_native_tls::{native_tls, TlsAcceptor};
+use tokio_tungstenite::tungstenite::error::UrlError;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::http::{HeaderMap, HeaderValue, Uri};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{accept_hdr_async_with_config, client_async_tls_with_config, Connector};
+
+use crate::auth::{Authenticator, Identity, Rejection, UpgradeRequest};
+use crate::settings::{StaticFiles, WebsocketServerSettings, WebsocketSettings};
+use crate::static_files::{read_head, refuse_full, serve, Replay};
+use crate::{requested_session, session_protocol};
+
+// how often listen loops wake up to check if they should keep running
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Anything that can carry websocket messages to and from a single peer
+pub trait MessageStream:
+    Stream<Item = Result<Message, WsError>> + Sink<Message, Error = WsError> + Send
+{
+}
+
+impl<T> MessageStream for T where
+    T: Stream<Item = Result<Message, WsError>> + Sink<Message, Error = WsError> + Send
+{
+}
+
+pub type Connection = Pin<Box<dyn MessageStream>>;
+
+type WrapConnection = Arc<dyn Fn(Connection) -> Connection + Send + Sync>;
+
+/// How the server accepts connections and how clients open them
+pub trait Transport: Send + Sync + 'static {
+    /// Accepts connections on `address` and hands them to `listener` until it stops running
+    fn listen(
+        &self,
+        address: String,
+        listener: Listener,
+        settings: WebsocketServerSettings,
+        task_pool: IoTaskPool,
+    );
+    /// Opens a connection with the upgrade `request`, addresses turn into one with
+    /// [`tokio_tungstenite::tungstenite::client::IntoClientRequest`]
+    fn connect(
+        &self,
+        request: Request,
+        settings: WebsocketSettings,
+        task_pool: IoTaskPool,
+    ) -> BoxFuture<'static, Result<Connection, WsError>>;
+}
+
+/// The server's end of a [`Transport::listen`], used to pass on new connections
+/// and to report when the transport starts and stops listening
+#[derive(Clone)]
+pub struct Listener {
+    pub(crate) running: Arc<Mutex<bool>>,
+    pub(crate) listening: Arc<AtomicBool>,
+    pub(crate) bound_address: Arc<Mutex<Option<SocketAddr>>>,
+    pub(crate) connections: Arc<AtomicUsize>,
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) new_connections: Arc<Mutex<VecDeque<IncomingConnection>>>,
+    pub(crate) wrap_connection: Option<WrapConnection>,
+    pub(crate) authenticator: Option<Authenticator>,
+    pub(crate) allowed_origins: Vec<String>,
+}
+
+/// A connection that completed the upgrade and is waiting for the server to pick it up
+pub struct IncomingConnection {
+    pub connection: Connection,
+    pub identity: Option<Identity>,
+    /// Where the connection came from, `None` for transports without addresses
+    pub peer_addr: Option<SocketAddr>,
+    /// The path and query string of the upgrade request
+    pub uri: Uri,
+    /// The headers of the upgrade request
+    pub headers: HeaderMap,
+    /// The session the client asked to resume, see [`crate::WebsocketResource::resume`]
+    pub resume_token: Option<String>,
+}
+
+impl Listener {
+    /// Turns false when the server shuts down, the transport should then stop listening
+    pub fn is_running(&self) -> bool {
+        *self.running.lock().expect("lock")
+    }
+    /// True when the server already has as many websocket connections as
+    /// [`WebsocketServerSettings::max_connections`] allows
+    pub fn is_full(&self) -> bool {
+        match self.max_connections {
+            Some(max_connections) => self.connections.load(Ordering::SeqCst) >= max_connections,
+            None => false,
+        }
+    }
+    /// Passes every accepted connection through `wrap` before the server gets it,
+    /// for transports that add behaviour on top of another transport
+    pub fn map(mut self, wrap: impl Fn(Connection) -> Connection + Send + Sync + 'static) -> Self {
+        self.wrap_connection = Some(match self.wrap_connection.take() {
+            Some(inner) => Arc::new(move |connection| wrap(inner(connection))),
+            None => Arc::new(wrap),
+        });
+        self
+    }
+    pub fn started(&self, bound_address: Option<SocketAddr>) {
+        *self.bound_address.lock().expect("lock") = bound_address;
+        self.listening.store(true, Ordering::SeqCst);
+    }
+    pub fn stopped(&self) {
+        *self.bound_address.lock().expect("lock") = None;
+        self.listening.store(false, Ordering::SeqCst);
+    }
+    /// Checks the `Origin` of an upgrade request against
+    /// [`WebsocketServerSettings::allowed_origins`] and runs the server's authenticator on it,
+    /// without one everyone gets in anonymously
+    pub fn authenticate(&self, request: &Request) -> Result<Option<Identity>, Rejection> {
+        self.check_origin(request)?;
+        match &self.authenticator {
+            Some(authenticator) => authenticator(&UpgradeRequest(request)).map(Some),
+            None => Ok(None),
+        }
+    }
+    // browsers always send the page's origin, other clients usually don't send one at all
+    fn check_origin(&self, request: &Request) -> Result<(), Rejection> {
+        if self.allowed_origins.is_empty() {
+            return Ok(());
+        }
+        let origin = match request.headers().get("origin") {
+            Some(origin) => origin.to_str().unwrap_or_default(),
+            None => return Ok(()),
+        };
+        let allowed = self
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin));
+        if allowed {
+            Ok(())
+        } else {
+            Err(Rejection::forbidden(format!(
+                "origin {} not allowed",
+                origin
+            )))
+        }
+    }
+    pub fn accept(&self, mut incoming: IncomingConnection) {
+        if let Some(wrap_connection) = &self.wrap_connection {
+            incoming.connection = wrap_connection(incoming.connection);
+        }
+        self.new_connections
+            .lock()
+            .expect("lock to send a new client")
+            .push_back(incoming);
+    }
+}
+
+/// Websockets over TCP, what the server and clients use unless told otherwise.
+/// Plain `ws://` by default, see [`TcpTransport::with_tls`] for `wss://`
+#[derive(Default, Clone)]
+pub struct TcpTransport {
+    acceptor: Option<TlsAcceptor>,
+    connector: Option<native_tls::TlsConnector>,
+}
+
+impl TcpTransport {
+    /// Terminates TLS on accepted connections using a PEM encoded certificate chain
+    /// and PKCS #8 private key, so clients have to connect with `wss://`
+    pub fn with_tls(
+        certificate: impl AsRef<Path>,
+        private_key: impl AsRef<Path>,
+    ) -> Result<Self, TlsError> {
+        let certificate = std::fs::read(certificate)?;
+        let private_key = std::fs::read(private_key)?;
+        let identity = native_tls::Identity::from_pkcs8(&certificate, &private_key)?;
+        let acceptor = native_tls::TlsAcceptor::new(identity)?;
+        Ok(Self {
+            acceptor: Some(acceptor.into()),
+            connector: None,
+        })
+    }
+    /// Trusts a PEM encoded certificate on top of the system's when connecting with `wss://`,
+    /// for servers using a self-signed certificate
+    pub fn trusting(certificate: impl AsRef<Path>) -> Result<Self, TlsError> {
+        let certificate = native_tls::Certificate::from_pem(&std::fs::read(certificate)?)?;
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(certificate)
+            .build()?;
+        Ok(Self {
+            acceptor: None,
+            connector: Some(connector),
+        })
+    }
+}
+
+// completes the tls handshake if there's an acceptor and then the websocket upgrade,
+// `None` when the request was for one of the static files instead
+async fn upgrade(
+    stream: TcpStream,
+    acceptor: Option<TlsAcceptor>,
+    config: WebSocketConfig,
+    listener: &Listener,
+    static_files: Option<StaticFiles>,
+    task_pool: IoTaskPool,
+) -> Result<Option<IncomingConnection>, WsError> {
+    let peer_addr = stream.peer_addr().ok();
+    let incoming = match acceptor {
+        Some(acceptor) => {
+            let stream = acceptor
+                .accept(stream)
+                .await
+                .map_err(|e| WsError::Io(std::io::Error::other(e)))?;
+            upgrade_stream(stream, config, listener, static_files, task_pool).await?
+        }
+        None => upgrade_stream(stream, config, listener, static_files, task_pool).await?,
+    };
+    Ok(incoming.map(|incoming| IncomingConnection {
+        peer_addr,
+        ..incoming
+    }))
+}
+
+async fn upgrade_stream<S>(
+    mut stream: S,
+    config: WebSocketConfig,
+    listener: &Listener,
+    static_files: Option<StaticFiles>,
+    task_pool: IoTaskPool,
+) -> Result<Option<IncomingConnection>, WsError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let head = match static_files {
+        Some(files) => {
+            let head = read_head(&mut stream).await?;
+            if !head.is_upgrade_to(&files.websocket_path) {
+                // files can take longer to send than the upgrade is given
+                task_pool
+                    .spawn(Compat::new(async move {
+                        serve(stream, &files, &head)
+                            .await
+                            .unwrap_or_else(|e| info!("Failed to serve {}: {:?}", head.path, e));
+                    }))
+                    .detach();
+                return Ok(None);
+            }
+            if listener.is_full() {
+                info!("Refusing websocket upgrade, already at max connections");
+                refuse_full(stream)
+                    .await
+                    .unwrap_or_else(|e| info!("Failed to refuse {}: {:?}", head.path, e));
+                return Ok(None);
+            }
+            Some(head)
+        }
+        None => None,
+    };
+    let stream = Replay::new(head, stream);
+    let mut identity = None;
+    let mut uri = Uri::default();
+    let mut headers = HeaderMap::new();
+    let mut resume_token = None;
+    // the error type is dictated by tungstenite's callback
+    #[allow(clippy::result_large_err)]
+    let authenticate = |request: &Request, mut response: Response| {
+        uri = request.uri().clone();
+        headers = request.headers().clone();
+        resume_token = requested_session(request.headers());
+        match listener.authenticate(request) {
+            Ok(authenticated) => {
+                identity = authenticated;
+                // clients only accept a subprotocol they offered
+                if let Some(protocol) = resume_token
+                    .as_deref()
+                    .and_then(|token| HeaderValue::from_str(&session_protocol(token)).ok())
+                {
+                    response
+                        .headers_mut()
+                        .insert(SEC_WEBSOCKET_PROTOCOL, protocol);
+                }
+                Ok(response)
+            }
+            Err(rejection) => {
+                info!("Rejected {}: {:?}", request.uri(), rejection);
+                Err(rejection.into_response())
+            }
+        }
+    };
+    let ws_stream = accept_hdr_async_with_config(stream, authenticate, Some(config)).await?;
+    Ok(Some(IncomingConnection {
+        connection: Box::pin(ws_stream),
+        identity,
+        peer_addr: None,
+        uri,
+        headers,
+        resume_token,
+    }))
+}
+
+impl Transport for TcpTransport {
+    fn listen(
+        &self,
+        address: String,
+        listener: Listener,
+        settings: WebsocketServerSettings,
+        task_pool: IoTaskPool,
+    ) {
+        let handshake_pool = task_pool.clone();
+        let acceptor = self.acceptor.clone();
+        // Task to listen to new connections
+        task_pool
+            .spawn(Compat::new(async move {
+                let tcp_listener = match TcpListener::bind(address.as_str()).await {
+                    Ok(tcp_listener) => tcp_listener,
+                    Err(e) => {
+                        warn!("Failed to listen on {}: {:?}", address, e);
+                        return;
+                    }
+                };
+                listener.started(tcp_listener.local_addr().ok());
+                while listener.is_running() {
+                    trace!("main listen loop");
+                    let (stream, peer) =
+                        match tokio::time::timeout(ACCEPT_POLL_INTERVAL, tcp_listener.accept())
+                            .await
+                        {
+                            Ok(Ok(accepted)) => accepted,
+                            Ok(Err(e)) => {
+                                info!("Failed to accept connection: {:?}", e);
+                                continue;
+                            }
+                            Err(_elapsed) => continue,
+                        };
+                    info!("New client: {:?}", peer);
+                    // with static files it's only known to be a websocket once the request is read
+                    if settings.static_files.is_none() && listener.is_full() {
+                        warn!("Dropping {:?}, already at max connections", peer);
+                        continue;
+                    }
+                    let listener = listener.clone();
+                    let handshake_timeout = settings.handshake_timeout;
+                    let config = settings.websocket_config();
+                    let acceptor = acceptor.clone();
+                    let static_files = settings.static_files.clone();
+                    let serving_pool = handshake_pool.clone();
+                    // upgrade in a separate task so a slow client doesn't hold up the listener
+                    handshake_pool
+                        .spawn(Compat::new(async move {
+                            match tokio::time::timeout(
+                                handshake_timeout,
+                                upgrade(
+                                    stream,
+                                    acceptor,
+                                    config,
+                                    &listener,
+                                    static_files,
+                                    serving_pool,
+                                ),
+                            )
+                            .await
+                            {
+                                Ok(Ok(Some(incoming))) => listener.accept(incoming),
+                                Ok(Ok(None)) => {}
+                                Ok(Err(e)) => info!("Failed to upgrade websocket: {:?}", e),
+                                Err(_elapsed) => {
+                                    info!("Websocket upgrade of {:?} timed out", peer)
+                                }
+                            }
+                        }))
+                        .detach();
+                }
+                drop(tcp_listener);
+                listener.stopped();
+                warn!("accept new connections loop closing");
+            }))
+            .detach();
+    }
+
+    fn connect(
+        &self,
+        request: Request,
+        settings: WebsocketSettings,
+        _task_pool: IoTaskPool,
+    ) -> BoxFuture<'static, Result<Connection, WsError>> {
+        let connector = self.connector.clone().map(Connector::NativeTls);
+        let config = settings.websocket_config();
+        let connect = async move {
+            let host = request
+                .uri()
+                .host()
+                .ok_or(WsError::Url(UrlError::NoHostName))?
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string();
+            let port = request
+                .uri()
+                .port_u16()
+                .or_else(|| match request.uri().scheme_str() {
+                    Some("wss") => Some(443),
+                    Some("ws") => Some(80),
+                    _ => None,
+                })
+                .ok_or(WsError::Url(UrlError::UnsupportedUrlScheme))?;
+            let stream = TcpStream::connect((host.as_str(), port)).await?;
+            let (ws_stream, _) =
+                client_async_tls_with_config(request, stream, Some(config), connector).await?;
+            Ok(Box::pin(ws_stream) as Connection)
+        };
+        async move {
+            tokio::time::timeout(settings.handshake_timeout, connect)
+                .await
+                .map_err(|_elapsed| {
+                    WsError::Io(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "timed out connecting",
+                    ))
+                })?
+        }
+        .boxed()
+    }
+}
+
+/// Why [`TcpTransport::with_tls`] or [`TcpTransport::trusting`] couldn't be set up
+#[derive(Debug)]
+pub enum TlsError {
+    Io(std::io::Error),
+    Tls(native_tls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Io(e) => write!(f, "failed to read certificate: {}", e),
+            TlsError::Tls(e) => write!(f, "invalid certificate: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<std::io::Error> for TlsError {
+    fn from(e: std::io::Error) -> Self {
+        TlsError::Io(e)
+    }
+}
+
+impl From<native_tls::Error> for TlsError {
+    fn from(e: native_tls::Error) -> Self {
+        TlsError::Tls(e)
+    }
+}
+
+/// Connects servers and clients in the same process through channels instead of sockets.
+/// Share a clone between the Apps that should be able to reach each other
+#[derive(Clone, Default)]
+pub struct LoopbackTransport {
+    servers: Arc<Mutex<HashMap<String, Listener>>>,
+}
+
+impl LoopbackTransport {
+    // clients connect to urls while servers listen on host:port, both should end up the same
+    fn key(address: &str) -> String {
+        let address = address
+            .trim_start_matches("ws://")
+            .trim_start_matches("wss://");
+        let end = address.find(['/', '?']).unwrap_or(address.len());
+        address[..end].to_string()
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn listen(
+        &self,
+        address: String,
+        listener: Listener,
+        _settings: WebsocketServerSettings,
+        task_pool: IoTaskPool,
+    ) {
+        let key = Self::key(&address);
+        self.servers
+            .lock()
+            .expect("lock")
+            .insert(key.clone(), listener.clone());
+        listener.started(None);
+        let servers = self.servers.clone();
+        task_pool
+            .spawn(async move {
+                while listener.is_running() {
+                    async_io::Timer::after(ACCEPT_POLL_INTERVAL).await;
+                }
+                let mut servers = servers.lock().expect("lock");
+                // a new server might have taken over the address already
+                if let Some(current) = servers.get(&key) {
+                    if Arc::ptr_eq(&current.running, &listener.running) {
+                        servers.remove(&key);
+                    }
+                }
+                listener.stopped();
+            })
+            .detach();
+    }
+
+    fn connect(
+        &self,
+        request: Request,
+        _settings: WebsocketSettings,
+        _task_pool: IoTaskPool,
+    ) -> BoxFuture<'static, Result<Connection, WsError>> {
+        let address = request.uri().to_string();
+        let listener = self
+            .servers
+            .lock()
+            .expect("lock")
+            .get(&Self::key(&address))
+            .filter(|listener| listener.is_running() && !listener.is_full())
+            .cloned();
+        async move {
+            let listener = listener.ok_or_else(|| {
+                WsError::Io(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionRefused,
+                    format!("nothing listening on {}", address),
+                ))
+            })?;
+            let identity = listener
+                .authenticate(&request)
+                .map_err(|rejection| WsError::Http(rejection.into_response()))?;
+            let (client, server) = LoopbackConnection::pair();
+            listener.accept(IncomingConnection {
+                connection: Box::pin(server),
+                identity,
+                peer_addr: None,
+                uri: request.uri().clone(),
+                headers: request.headers().clone(),
+                resume_token: requested_session(request.headers()),
+            });
+            Ok(Box::pin(client) as Connection)
+        }
+        .boxed()
+    }
+}
+
+/// One end of an in memory connection, behaves like a websocket in that pings are answered
+/// and a close frame ends the connection for both sides
+pub struct LoopbackConnection {
+    incoming: UnboundedReceiver<Message>,
+    outgoing: UnboundedSender<Message>,
+    closed: bool,
+}
+
+impl LoopbackConnection {
+    pub fn pair() -> (Self, Self) {
+        let (a_outgoing, b_incoming) = unbounded();
+        let (b_outgoing, a_incoming) = unbounded();
+        (
+            Self {
+                incoming: a_incoming,
+                outgoing: a_outgoing,
+                closed: false,
+            },
+            Self {
+                incoming: b_incoming,
+                outgoing: b_outgoing,
+                closed: false,
+            },
+        )
+    }
+}
+
+impl Stream for LoopbackConnection {
+    type Item = Result<Message, WsError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.closed {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut self.incoming).poll_next(cx) {
+            Poll::Ready(Some(Message::Ping(payload))) => {
+                let _ = self.outgoing.unbounded_send(Message::Pong(payload.clone()));
+                Poll::Ready(Some(Ok(Message::Ping(payload))))
+            }
+            Poll::Ready(Some(Message::Close(frame))) => {
+                // answer the close and hang up like a websocket would
+                let _ = self.outgoing.unbounded_send(Message::Close(None));
+                self.outgoing.close_channel();
+                self.closed = true;
+                Poll::Ready(Some(Ok(Message::Close(frame))))
+            }
+            Poll::Ready(Some(msg)) => Poll::Ready(Some(Ok(msg))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Sink<Message> for LoopbackConnection {
+    type Error = WsError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let is_close = matches!(item, Message::Close(_));
+        self.outgoing
+            .unbounded_send(item)
+            .map_err(|_| WsError::AlreadyClosed)?;
+        if is_close {
+            self.outgoing.close_channel();
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.outgoing.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
@@ -1,49 +1,95 @@
-use std::collections::VecDeque;
-use std::future::Future;
 use std::sync::{Arc, Mutex};
-use std::task::Poll;
 
 use bevy::prelude::*;
 use bevy::tasks::IoTaskPool;
 use log::info;
+use log::trace;
 use log::warn;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
-use web_sys::{ErrorEvent, MessageEvent, WebSocket};
+use web_sys::{CloseEvent, ErrorEvent, MessageEvent, WebSocket};
 
 use crate::event_stream::EventStream;
-use crate::WebsocketClientEvent;
+use crate::settings::WebsocketSettings;
+use crate::traffic::{ClientDiagnostics, TrafficCounters, TrafficStats};
+use crate::{parse_welcome_message, session_protocol, WebsocketClientEvent};
 
-pub struct WebsocketPlugin;
+// how often the send loop checks for new messages to send
+const SEND_POLL_INTERVAL_MS: i32 = 10;
 
-struct MessageOffset(usize); // todo remove
+pub struct WebsocketPlugin;
 
 impl Plugin for WebsocketPlugin {
-    fn build(&self, app: &mut AppBuilder) {
+    fn build(&self, app: &mut App) {
+        app.world
+            .get_resource_or_insert_with(WebsocketSettings::default);
         app.insert_resource(WebsocketResource::default());
         app.add_event::<WebsocketClientEvent>();
-        app.add_system(setup_websocket_system.system());
-        app.insert_resource(MessageOffset(0));
-        app.add_system(write_websocket_event_to_client.system());
+        app.add_event::<ClientDiagnostics>();
+        app.add_system(setup_websocket_system);
+        app.add_system(write_websocket_event_to_client);
+        app.add_system(send_client_diagnostics);
+    }
+}
+
+// std's Instant panics in the browser
+fn send_client_diagnostics(
+    resource: Res<WebsocketResource>,
+    settings: Res<WebsocketSettings>,
+    mut last_sent: Local<Option<f64>>,
+    mut diagnostics: EventWriter<ClientDiagnostics>,
+) {
+    let interval_ms = match settings.diagnostics_interval {
+        Some(interval) => interval.as_millis() as f64,
+        None => return,
+    };
+    let now = js_sys::Date::now();
+    if now - *last_sent.get_or_insert(now) < interval_ms {
+        return;
     }
+    *last_sent = Some(now);
+    diagnostics.send(ClientDiagnostics(resource.traffic()));
 }
 
 fn write_websocket_event_to_client(
     mut event_writer: EventWriter<WebsocketClientEvent>,
     mut resource: ResMut<WebsocketResource>,
+    settings: Res<WebsocketSettings>,
 ) {
     while let Some(msg) = resource.ws_to_event_channel_receiver.next() {
-        info!("system pusing event {:?} to client", msg);
+        if settings.log_payloads {
+            info!("system pusing event {:?} to client", msg);
+        }
         event_writer.send(msg);
     }
 }
 
-fn setup_websocket_system(mut resource: ResMut<WebsocketResource>, task_pool: Res<IoTaskPool>) {
-    if let Some(address) = resource.address_to_connect_to.take() {
-        let ws: WebSocket = WebSocket::new(address.as_str()).unwrap();
+fn setup_websocket_system(
+    mut resource: ResMut<WebsocketResource>,
+    task_pool: Res<IoTaskPool>,
+    settings: Res<WebsocketSettings>,
+) {
+    if let Some(address) = resource.address.take() {
+        info!("Have an address to connect to: {address}");
+        let opened = match resource.resume_token.take() {
+            Some(token) => WebSocket::new_with_str(&address, &session_protocol(&token)),
+            None => WebSocket::new(&address),
+        };
+        let ws: WebSocket = match opened {
+            Ok(ws) => ws,
+            Err(e) => {
+                warn!("Failed to connect to {}: {:?}", address, e);
+                resource
+                    .ws_to_event_channel_receiver
+                    .buffer()
+                    .lock()
+                    .expect("aquire lock")
+                    .push_back(WebsocketClientEvent::OnClose);
+                return;
+            }
+        };
         {
             // On Error
-            let _buffer = resource.ws_to_event_channel_receiver.buffer().clone();
             let onerror_callback = Closure::wrap(Box::new(move |e: ErrorEvent| {
                 warn!("WS error: {:?}", e);
             }) as Box<dyn FnMut(ErrorEvent)>);
@@ -53,20 +99,31 @@ fn setup_websocket_system(mut resource: ResMut<WebsocketResource>, task_pool: Re
 
         {
             // On Message
-            let buffer = resource.ws_to_event_channel_receiver.buffer().clone();
+            let buffer = resource.ws_to_event_channel_receiver.buffer();
+            let session_token = resource.session_token.clone();
+            let log_payloads = settings.log_payloads;
+            let max_message_size = settings.max_message_size;
+            let traffic = resource.traffic.clone();
             let onmessage_callback = Closure::wrap(Box::new(move |e: MessageEvent| {
                 if let Ok(txt) = e.data().dyn_into::<js_sys::JsString>() {
                     let str: String = txt.into();
-                    info!("Client received: {:?}", str);
-                    if str.contains("##CLIENT_ID##") {
-                        let mut split = str.split(" ");
-                        split.next();
-                        let id = split.next();
-                        let id = id.unwrap();
+                    traffic.received(str.len());
+                    if log_payloads {
+                        info!("Client received: {:?}", str);
+                    }
+                    if let Some(max_message_size) = max_message_size {
+                        if str.len() > max_message_size {
+                            warn!("Dropping message of {} bytes", str.len());
+                            traffic.dropped();
+                            return;
+                        }
+                    }
+                    if let Some((id, token)) = parse_welcome_message(&str) {
+                        *session_token.lock().expect("aquire lock") = token;
                         buffer
                             .lock()
                             .expect("aquire lock")
-                            .push_back(WebsocketClientEvent::OnOpen(id.parse().unwrap()));
+                            .push_back(WebsocketClientEvent::OnOpen(id));
                     } else {
                         buffer
                             .lock()
@@ -81,20 +138,20 @@ fn setup_websocket_system(mut resource: ResMut<WebsocketResource>, task_pool: Re
 
         {
             // on close
-            let buffer = resource.ws_to_event_channel_receiver.buffer().clone();
-            let onclose_callback = Closure::wrap(Box::new(move |_| {
+            let buffer = resource.ws_to_event_channel_receiver.buffer();
+            let onclose_callback = Closure::wrap(Box::new(move |e: CloseEvent| {
+                warn!("Server closed the connection: {} {}", e.code(), e.reason());
                 buffer
                     .lock()
                     .expect("aquire lock")
                     .push_back(WebsocketClientEvent::OnClose);
-            }) as Box<dyn FnMut(JsValue)>);
+            }) as Box<dyn FnMut(CloseEvent)>);
             ws.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
             onclose_callback.forget();
         }
 
         {
             // on open
-            let _buffer = resource.ws_to_event_channel_receiver.buffer().clone();
             let onopen_callback = Closure::wrap(Box::new(move |_| {
                 info!("Ws opened");
             }) as Box<dyn FnMut(JsValue)>);
@@ -102,40 +159,54 @@ fn setup_websocket_system(mut resource: ResMut<WebsocketResource>, task_pool: Re
             onopen_callback.forget();
         }
 
-        // todo have event stream impl clone and use that here instead
         let message_to_be_sent_over_ws = resource.message_to_be_sent_over_ws.buffer();
+        // every connection gets its own flag so closing the last one doesn't stop a reopened one
+        resource.run_listen_loop = Arc::new(Mutex::new(true));
         let running = resource.run_listen_loop.clone();
+        let traffic = resource.traffic.clone();
+        let handshake_timeout_ms = settings.handshake_timeout.as_millis() as f64;
+        let started_at = js_sys::Date::now();
+        // the browser does the actual io, this loop hands it our messages once the socket is open
+        // and closes the socket when we're told to stop
         task_pool
             .spawn(async move {
-                pub fn next_event(
-                    buffer: Arc<Mutex<VecDeque<String>>>,
-                ) -> impl Future<Output = Option<String>> {
-                    use futures_util::future::poll_fn;
-                    poll_fn(move |_cx| {
-                        let mut buffer = buffer
-                            .lock()
-                            .expect("EventStream.next_event failed to obtain lock");
-                        match buffer.pop_front() {
-                            Some(event) => Poll::Ready(Some(event)),
-                            None => Poll::Ready(None),
-                        }
-                    })
-                }
-
                 while *running.lock().expect("lock") {
-                    if let Some(msg) = next_event(message_to_be_sent_over_ws.clone()).await {
-                        ws.send_with_str(msg.as_str())
-                            .expect("wsclient.send failed");
+                    match ws.ready_state() {
+                        WebSocket::CONNECTING => {
+                            if js_sys::Date::now() - started_at > handshake_timeout_ms {
+                                warn!("Timed out connecting");
+                                break;
+                            }
+                        }
+                        WebSocket::OPEN => {
+                            while let Some(msg) =
+                                message_to_be_sent_over_ws.lock().expect("lock").pop_front()
+                            {
+                                match ws.send_with_str(msg.as_str()) {
+                                    Ok(()) => traffic.sent(msg.len()),
+                                    Err(e) => {
+                                        warn!("Send failed:{:?}", e);
+                                        traffic.dropped();
+                                    }
+                                }
+                            }
+                        }
+                        _ => {
+                            trace!("ws closed, stopping send loop");
+                            break;
+                        }
                     }
-                    let _ = wasm_bindgen_futures::JsFuture::from(sleep(10)).await;
+                    let _ =
+                        wasm_bindgen_futures::JsFuture::from(sleep(SEND_POLL_INTERVAL_MS)).await;
                 }
+                ws.close().unwrap_or_else(|e| warn!("Close failed:{:?}", e));
+                warn!("ws poll stopping");
             })
             .detach();
     }
 }
 
-#[wasm_bindgen]
-pub fn sleep(ms: i32) -> js_sys::Promise {
+fn sleep(ms: i32) -> js_sys::Promise {
     js_sys::Promise::new(&mut |resolve, _| {
         web_sys::window()
             .unwrap()
@@ -146,35 +217,66 @@ pub fn sleep(ms: i32) -> js_sys::Promise {
 
 // use by the client to talk to server
 pub struct WebsocketResource {
+    address: Option<String>,
+    // the session the next connection asks to resume
+    resume_token: Option<String>,
     pub ws_to_event_channel_receiver: EventStream<WebsocketClientEvent>,
-    address_to_connect_to: Option<String>,
     pub message_to_be_sent_over_ws: EventStream<String>,
     run_listen_loop: Arc<Mutex<bool>>,
+    session_token: Arc<Mutex<Option<String>>>,
+    // every connection since the resource was created
+    traffic: Arc<TrafficCounters>,
 }
 
 impl Default for WebsocketResource {
     fn default() -> Self {
         Self {
-            address_to_connect_to: None,
+            address: None,
+            resume_token: None,
             ws_to_event_channel_receiver: Default::default(),
             message_to_be_sent_over_ws: Default::default(),
             run_listen_loop: Arc::new(Mutex::new(true)),
+            session_token: Default::default(),
+            traffic: Default::default(),
         }
     }
 }
 
 impl WebsocketResource {
     pub fn open(&mut self, address: &str) {
-        self.address_to_connect_to.replace(address.to_string());
+        self.address = Some(address.to_string());
+        self.resume_token = None;
+    }
+    /// Opens `address` again presenting the session token from the last connection so the
+    /// server hands back the same client id
+    pub fn resume(&mut self, address: &str) {
+        self.open(address);
+        self.resume_token = self.session_token();
+    }
+    /// The secret the server handed out with our client id, only share it with the server
+    pub fn session_token(&self) -> Option<String> {
+        self.session_token.lock().unwrap().clone()
     }
     pub fn broadcast(&self, message: String) {
+        trace!("Added {:?} to message_to_be_sent_over_ws", message);
         self.message_to_be_sent_over_ws
             .buffer()
             .lock()
             .expect("lock")
             .push_back(message);
     }
+    /// What went over every connection this resource opened
+    pub fn traffic(&self) -> TrafficStats {
+        let queued = self
+            .message_to_be_sent_over_ws
+            .buffer()
+            .lock()
+            .expect("lock")
+            .len();
+        self.traffic.stats(queued)
+    }
     pub fn close(&self) {
+        info!("Sending shutdown signal");
         *self.run_listen_loop.lock().unwrap() = false;
     }
 }
@@ -38,8 +38,8 @@ mod tests {
         App::new()
             .add_plugins(MinimalPlugins)
             .insert_resource(Running(Arc::new(Mutex::new(true))))
-            .add_system(shutdown.system())
-            .add_startup_system(setup_async_countdown.system())
+            .add_system(shutdown)
+            .add_startup_system(setup_async_countdown)
             .run();
     }
 }
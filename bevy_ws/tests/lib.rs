@@ -1,18 +1,44 @@
 #[cfg(test)]
 mod figure_out_bevy_async;
 #[cfg(test)]
-#[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use bevy::app::AppExit;
+    use bevy::ecs::event::{Events, ManualEventReader};
     use bevy::prelude::*;
+    use bevy::tasks::{IoTaskPool, TaskPool};
+    use futures::{FutureExt, SinkExt, StreamExt};
     use log::info;
     use log::LevelFilter;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
     use serde::Deserialize;
     use serde::Serialize;
+    use std::net::{IpAddr, SocketAddr};
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::error::CapacityError;
+    use tokio_tungstenite::tungstenite::http::{Request, StatusCode};
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::Error as WsError;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
 
     use bevy_ws::client::{WebsocketPlugin, WebsocketResource};
+    use bevy_ws::conditioner::condition;
     use bevy_ws::server::{WebsocketServerPlugin, WebsocketServerResource};
-    use bevy_ws::{WebsocketClientEvent, WebsocketServerEvent};
+    use bevy_ws::transport::Connection;
+    use bevy_ws::transport::LoopbackConnection;
+    use bevy_ws::{
+        ConditionedTransport, ConnectionCount, Identity, Limit, LoopbackTransport,
+        NetworkConditions, Overflow, RateLimit, Rejection, ServerDiagnostics, StaticFiles,
+        TcpTransport, Transport, UpgradeRequest, WebsocketClientEvent, WebsocketServerEvent,
+        WebsocketServerSettings, WebsocketSettings, SERVER_FULL,
+    };
 
     const GOAL: u32 = 10;
 
@@ -31,11 +57,149 @@ mod tests {
         ws.listen(format!("localhost:{}", port.0).as_str());
     }
 
+    const CERTIFICATE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/localhost.crt");
+    const PRIVATE_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/localhost.key");
+
+    fn startup_tls_server(mut ws: ResMut<WebsocketServerResource>, port: Res<PortResource>) {
+        ws.use_tls(CERTIFICATE, PRIVATE_KEY).unwrap();
+        ws.listen(format!("localhost:{}", port.0).as_str());
+    }
+
+    fn startup_tls_client(mut ws: ResMut<WebsocketResource>, port: Res<PortResource>) {
+        ws.set_transport(TcpTransport::trusting(CERTIFICATE).unwrap());
+        ws.open(format!("wss://localhost:{}", port.0).as_str());
+    }
+
+    fn startup_untrusting_tls_client(mut ws: ResMut<WebsocketResource>, port: Res<PortResource>) {
+        ws.open(format!("wss://localhost:{}", port.0).as_str());
+    }
+
     fn startup_client(mut ws: ResMut<WebsocketResource>, port: Res<PortResource>) {
         info!("Client started");
         ws.open(format!("ws://localhost:{}", port.0).as_str());
     }
 
+    struct Loopback(LoopbackTransport);
+
+    struct Conditions(NetworkConditions);
+
+    fn use_conditioned_loopback_server(
+        mut ws: ResMut<WebsocketServerResource>,
+        loopback: Res<Loopback>,
+        conditions: Res<Conditions>,
+    ) {
+        ws.set_transport(ConditionedTransport::new(
+            loopback.0.clone(),
+            conditions.0.clone(),
+        ));
+    }
+
+    fn use_conditioned_loopback_client(
+        mut ws: ResMut<WebsocketResource>,
+        loopback: Res<Loopback>,
+        conditions: Res<Conditions>,
+    ) {
+        ws.set_transport(ConditionedTransport::new(
+            loopback.0.clone(),
+            conditions.0.clone(),
+        ));
+    }
+
+    // sends `count` numbered messages through a connection conditioned on the sending side
+    // and returns what came out the other end, in arrival order
+    fn send_conditioned(conditions: NetworkConditions, count: u32) -> Vec<String> {
+        let task_pool = IoTaskPool(TaskPool::new());
+        let (sender, mut receiver) = LoopbackConnection::pair();
+        let mut sender = condition(
+            Box::pin(sender),
+            conditions,
+            StdRng::seed_from_u64(7),
+            &task_pool,
+        );
+        futures::executor::block_on(async move {
+            for i in 0..count {
+                sender.send(WsMessage::Text(i.to_string())).await.unwrap();
+            }
+            sender.close().await.unwrap();
+            let mut received = vec![];
+            while let Some(Ok(WsMessage::Text(msg))) = receiver.next().await {
+                received.push(msg);
+            }
+            received
+        })
+    }
+
+    fn use_loopback_server(mut ws: ResMut<WebsocketServerResource>, loopback: Res<Loopback>) {
+        ws.set_transport(loopback.0.clone());
+    }
+
+    fn use_loopback_client(mut ws: ResMut<WebsocketResource>, loopback: Res<Loopback>) {
+        ws.set_transport(loopback.0.clone());
+    }
+
+    // steps every app in turn until all of them have asked to exit
+    fn run_until_exit(mut apps: Vec<App>) {
+        let mut readers: Vec<ManualEventReader<AppExit>> =
+            apps.iter().map(|_| Default::default()).collect();
+        let mut exited = vec![false; apps.len()];
+        for _ in 0..5000 {
+            for ((app, reader), exited) in apps.iter_mut().zip(&mut readers).zip(&mut exited) {
+                if *exited {
+                    continue;
+                }
+                app.update();
+                let events = app.world.resource::<Events<AppExit>>();
+                *exited = reader.iter(events).next().is_some();
+            }
+            if exited.iter().all(|exited| *exited) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("apps didn't exit, exited: {:?}", exited);
+    }
+
+    // clients connecting any earlier would find nothing there
+    fn update_until_listening(server: &mut App) {
+        update_until(server, WebsocketServerResource::is_listening);
+    }
+
+    // steps the server until `done` says so
+    fn update_until(server: &mut App, done: impl Fn(&WebsocketServerResource) -> bool) {
+        for _ in 0..5000 {
+            server.update();
+            if done(server.world.resource::<WebsocketServerResource>()) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("the server never got there");
+    }
+
+    // the port the OS picked for a server listening on port 0
+    fn listening_port(server: &App) -> u32 {
+        let ws = server.world.resource::<WebsocketServerResource>();
+        ws.local_addr()
+            .expect("the server isn't listening")
+            .port()
+            .into()
+    }
+
+    // steps the client and the server until the client asks to exit
+    fn update_until_exit(client: &mut App, server: &mut App) {
+        let mut reader = ManualEventReader::<AppExit>::default();
+        for _ in 0..5000 {
+            server.update();
+            client.update();
+            let events = client.world.resource::<Events<AppExit>>();
+            if reader.iter(events).next().is_some() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("the client didn't exit");
+    }
+
     fn network_bounce_client_single(
         ws: Res<WebsocketResource>,
         name: Res<NameResource>,
@@ -169,6 +333,513 @@ mod tests {
                 WebsocketServerEvent::OnClose(client_id) => {
                     info!("User {} disconnected", client_id);
                 }
+                _ => {}
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct SeenClients {
+        opened: Vec<u64>,
+        closed: Vec<u64>,
+    }
+
+    fn shutdown_server_when_client_connects(
+        mut ws: ResMut<WebsocketServerResource>,
+        mut ws_events: EventReader<WebsocketServerEvent>,
+        mut clients: Local<SeenClients>,
+        mut exit: EventWriter<AppExit>,
+    ) {
+        for event in ws_events.iter() {
+            match event {
+                WebsocketServerEvent::OnOpen(client_id) => {
+                    clients.opened.push(*client_id);
+                    ws.shutdown(Duration::from_secs(2));
+                }
+                WebsocketServerEvent::OnClose(client_id) => clients.closed.push(*client_id),
+                WebsocketServerEvent::ServerStopped => {
+                    assert_eq!(clients.closed, clients.opened);
+                    exit.send(AppExit);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn exit_when_server_closes(
+        mut ws_events: EventReader<WebsocketClientEvent>,
+        mut exit: EventWriter<AppExit>,
+    ) {
+        for event in ws_events.iter() {
+            if let WebsocketClientEvent::OnClose = event {
+                exit.send(AppExit);
+            }
+        }
+    }
+
+    fn move_server_when_first_client_connects(
+        mut ws: ResMut<WebsocketServerResource>,
+        mut ws_events: EventReader<WebsocketServerEvent>,
+        mut moved_from: Local<Option<SocketAddr>>,
+        mut exit: EventWriter<AppExit>,
+    ) {
+        for event in ws_events.iter() {
+            match event {
+                WebsocketServerEvent::OnOpen(_) if moved_from.is_none() => {
+                    *moved_from = ws.local_addr();
+                    ws.listen("localhost:0");
+                }
+                WebsocketServerEvent::OnOpen(_) => {
+                    assert_ne!(ws.local_addr(), *moved_from);
+                    exit.send(AppExit);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn exit_when_connected(
+        mut ws_events: EventReader<WebsocketClientEvent>,
+        mut exit: EventWriter<AppExit>,
+    ) {
+        for event in ws_events.iter() {
+            if let WebsocketClientEvent::OnOpen(_) = event {
+                exit.send(AppExit);
+            }
+        }
+    }
+
+    // lets in clients that have the token in the query string or a cookie
+    fn authenticate(request: &UpgradeRequest) -> Result<Identity, Rejection> {
+        let token = request
+            .query("token")
+            .or_else(|| request.cookie("token").map(str::to_string));
+        match token.as_deref() {
+            Some("secret") => Ok(Identity("alice".to_string())),
+            Some(_) => Err(Rejection::forbidden("wrong token")),
+            None => Err(Rejection::unauthorized("missing token")),
+        }
+    }
+
+    fn startup_authenticating_server(
+        mut ws: ResMut<WebsocketServerResource>,
+        port: Res<PortResource>,
+    ) {
+        ws.set_authenticator(authenticate);
+        ws.listen(format!("localhost:{}", port.0).as_str());
+    }
+
+    fn startup_client_in_room(mut ws: ResMut<WebsocketResource>, port: Res<PortResource>) {
+        ws.open(format!("ws://localhost:{}/play?room=red%20team", port.0).as_str());
+    }
+
+    fn startup_client_with_token(mut ws: ResMut<WebsocketResource>, port: Res<PortResource>) {
+        ws.open(format!("ws://localhost:{}/?token=secret", port.0).as_str());
+    }
+
+    fn exit_when_alice_connects(
+        ws: Res<WebsocketServerResource>,
+        mut ws_events: EventReader<WebsocketServerEvent>,
+        mut exit: EventWriter<AppExit>,
+    ) {
+        for event in ws_events.iter() {
+            if let WebsocketServerEvent::OnOpen(client_id) = event {
+                assert_eq!(
+                    ws.identity(*client_id),
+                    Some(&Identity("alice".to_string()))
+                );
+                exit.send(AppExit);
+            }
+        }
+    }
+
+    struct PlayerName(String);
+
+    fn send_hello_and_exit(
+        ws: Res<WebsocketResource>,
+        mut ws_events: EventReader<WebsocketClientEvent>,
+        mut exit: EventWriter<AppExit>,
+    ) {
+        for event in ws_events.iter() {
+            if let WebsocketClientEvent::OnOpen(_) = event {
+                ws.broadcast("hello".to_string());
+                exit.send(AppExit);
+            }
+        }
+    }
+
+    fn name_clients_and_exit_on_message(
+        mut ws: ResMut<WebsocketServerResource>,
+        mut ws_events: EventReader<WebsocketServerEvent>,
+        mut exit: EventWriter<AppExit>,
+    ) {
+        for event in ws_events.iter() {
+            match event {
+                WebsocketServerEvent::OnOpen(client_id) => {
+                    let client = ws.client_mut(*client_id).unwrap();
+                    client.insert(PlayerName(format!("Player {}", client_id)));
+                }
+                WebsocketServerEvent::OnMessage(client_id, _) => {
+                    let client = ws.client(*client_id).unwrap();
+                    assert_eq!(
+                        client.get::<PlayerName>().map(|name| name.0.clone()),
+                        Some(format!("Player {}", client_id))
+                    );
+                    assert!(client.connected_at <= Instant::now());
+                    assert_eq!(client.peer_addr, None);
+                    assert_eq!(client.uri.path(), "/play");
+                    assert_eq!(client.query("room").as_deref(), Some("red team"));
+                    assert_eq!(client.query("name"), None);
+                    assert_eq!(ws.clients().count(), 1);
+                    exit.send(AppExit);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn send_session_token_and_exit_on_reply(
+        ws: Res<WebsocketResource>,
+        mut ws_events: EventReader<WebsocketClientEvent>,
+        mut exit: EventWriter<AppExit>,
+    ) {
+        for event in ws_events.iter() {
+            match event {
+                WebsocketClientEvent::OnOpen(_) => ws.broadcast(ws.session_token().unwrap()),
+                WebsocketClientEvent::OnMessage(msg) => {
+                    assert_eq!(msg, "it's you");
+                    exit.send(AppExit);
+                }
+                WebsocketClientEvent::OnClose => {}
+            }
+        }
+    }
+
+    fn reply_to_client_with_matching_token(
+        ws: Res<WebsocketServerResource>,
+        mut ws_events: EventReader<WebsocketServerEvent>,
+        mut exit: EventWriter<AppExit>,
+    ) {
+        for event in ws_events.iter() {
+            if let WebsocketServerEvent::OnMessage(client_id, token) = event {
+                assert_eq!(ws.client_by_token(token), Some(*client_id));
+                assert_eq!(ws.client_by_token("not a token"), None);
+                ws.send(*client_id, "it's you".to_string());
+                exit.send(AppExit);
+            }
+        }
+    }
+
+    struct ServerEvents(Arc<Mutex<Vec<WebsocketServerEvent>>>);
+
+    struct ClientIds(Arc<Mutex<Vec<u64>>>);
+
+    // which server event the client waits for before resuming
+    struct ResumeAfter(fn(&WebsocketServerEvent) -> bool);
+
+    // keeps a log the client can look at, exits once a client opened or resumed twice
+    fn record_server_events(
+        mut ws: ResMut<WebsocketServerResource>,
+        mut ws_events: EventReader<WebsocketServerEvent>,
+        server_events: Res<ServerEvents>,
+        mut exit: EventWriter<AppExit>,
+    ) {
+        for event in ws_events.iter() {
+            if let WebsocketServerEvent::OnOpen(client_id) = event {
+                let client = ws.client_mut(*client_id).unwrap();
+                client.insert(PlayerName(format!("Player {}", client_id)));
+            }
+            if let WebsocketServerEvent::OnResume(client_id) = event {
+                let client = ws.client(*client_id).unwrap();
+                assert_eq!(
+                    client.get::<PlayerName>().map(|name| name.0.clone()),
+                    Some(format!("Player {}", client_id))
+                );
+                assert!(!ws.is_suspended(*client_id));
+            }
+            let mut server_events = server_events.0.lock().unwrap();
+            server_events.push(event.clone());
+            let opened = server_events
+                .iter()
+                .filter(|event| {
+                    matches!(
+                        event,
+                        WebsocketServerEvent::OnOpen(_) | WebsocketServerEvent::OnResume(_)
+                    )
+                })
+                .count();
+            if opened == 2 {
+                exit.send(AppExit);
+            }
+        }
+    }
+
+    // hangs up after the first connect and resumes once the server noticed
+    fn drop_connection_and_resume(
+        mut ws: ResMut<WebsocketResource>,
+        mut ws_events: EventReader<WebsocketClientEvent>,
+        server_events: Res<ServerEvents>,
+        client_ids: Res<ClientIds>,
+        resume_after: Res<ResumeAfter>,
+        mut resumed: Local<bool>,
+        mut exit: EventWriter<AppExit>,
+    ) {
+        for event in ws_events.iter() {
+            if let WebsocketClientEvent::OnOpen(client_id) = event {
+                let mut client_ids = client_ids.0.lock().unwrap();
+                client_ids.push(*client_id);
+                match client_ids.len() {
+                    1 => ws.close(),
+                    _ => exit.send(AppExit),
+                }
+            }
+        }
+        let server_noticed = server_events.0.lock().unwrap().iter().any(resume_after.0);
+        if server_noticed && !*resumed {
+            *resumed = true;
+            ws.resume("ws://localhost:8080");
+        }
+    }
+
+    // runs a client that drops and resumes against a server with `session_grace_period`
+    fn drop_and_resume(
+        session_grace_period: Duration,
+        resume_after: ResumeAfter,
+    ) -> (Vec<WebsocketServerEvent>, Vec<u64>) {
+        let loopback = LoopbackTransport::default();
+        let server_events: Arc<Mutex<Vec<WebsocketServerEvent>>> = Default::default();
+        let client_ids: Arc<Mutex<Vec<u64>>> = Default::default();
+
+        let mut server = App::new();
+        server
+            .insert_resource(WebsocketServerSettings {
+                session_grace_period,
+                ..Default::default()
+            })
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_server)
+            .add_startup_system(startup_server)
+            .add_system(record_server_events)
+            .insert_resource(ServerEvents(server_events.clone()))
+            .insert_resource(Loopback(loopback.clone()))
+            .insert_resource(PortResource(8080));
+
+        let mut client = App::new();
+        client
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_client)
+            .add_startup_system(startup_client)
+            .add_system(drop_connection_and_resume)
+            .insert_resource(ServerEvents(server_events.clone()))
+            .insert_resource(ClientIds(client_ids.clone()))
+            .insert_resource(resume_after)
+            .insert_resource(Loopback(loopback))
+            .insert_resource(PortResource(8080));
+
+        run_until_exit(vec![server, client]);
+        let server_events = server_events.lock().unwrap().clone();
+        let client_ids = client_ids.lock().unwrap().clone();
+        (server_events, client_ids)
+    }
+
+    // what a client sends as soon as it's connected, it exits right after or once it's closed
+    struct Burst {
+        messages: Vec<&'static str>,
+        wait_for_close: bool,
+    }
+
+    fn send_burst_and_exit(
+        ws: Res<WebsocketResource>,
+        burst: Res<Burst>,
+        mut ws_events: EventReader<WebsocketClientEvent>,
+        mut exit: EventWriter<AppExit>,
+    ) {
+        for event in ws_events.iter() {
+            match event {
+                WebsocketClientEvent::OnOpen(_) => {
+                    for message in &burst.messages {
+                        ws.broadcast(message.to_string());
+                    }
+                    if !burst.wait_for_close {
+                        exit.send(AppExit);
+                    }
+                }
+                WebsocketClientEvent::OnClose => exit.send(AppExit),
+                WebsocketClientEvent::OnMessage(_) => {}
+            }
+        }
+    }
+
+    struct ExpectedMessages(usize);
+
+    // exits once as many messages as expected arrived or were dropped, or the client is gone
+    fn record_server_events_until_done(
+        mut ws_events: EventReader<WebsocketServerEvent>,
+        server_events: Res<ServerEvents>,
+        expected: Res<ExpectedMessages>,
+        mut exit: EventWriter<AppExit>,
+    ) {
+        for event in ws_events.iter() {
+            let mut server_events = server_events.0.lock().unwrap();
+            server_events.push(event.clone());
+            let handled = server_events
+                .iter()
+                .filter(|event| {
+                    matches!(
+                        event,
+                        WebsocketServerEvent::OnMessage(_, _)
+                            | WebsocketServerEvent::LimitExceeded(_, _)
+                    )
+                })
+                .count();
+            if handled == expected.0 || matches!(event, WebsocketServerEvent::OnClose(_)) {
+                exit.send(AppExit);
+            }
+        }
+    }
+
+    // runs a client sending `burst` against a server with `settings`
+    fn send_burst(
+        settings: WebsocketServerSettings,
+        burst: Burst,
+        expected: usize,
+    ) -> Vec<WebsocketServerEvent> {
+        let loopback = LoopbackTransport::default();
+        let server_events: Arc<Mutex<Vec<WebsocketServerEvent>>> = Default::default();
+
+        let mut server = App::new();
+        server
+            .insert_resource(settings)
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_server)
+            .add_startup_system(startup_server)
+            .add_system(record_server_events_until_done)
+            .insert_resource(ServerEvents(server_events.clone()))
+            .insert_resource(ExpectedMessages(expected))
+            .insert_resource(Loopback(loopback.clone()))
+            .insert_resource(PortResource(8080));
+
+        let mut client = App::new();
+        client
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_client)
+            .add_startup_system(startup_client)
+            .add_system(send_burst_and_exit)
+            .insert_resource(burst)
+            .insert_resource(Loopback(loopback))
+            .insert_resource(PortResource(8080));
+
+        run_until_exit(vec![server, client]);
+        let server_events = server_events.lock().unwrap().clone();
+        server_events
+    }
+
+    fn received(server_events: &[WebsocketServerEvent]) -> Vec<&str> {
+        server_events
+            .iter()
+            .filter_map(|event| match event {
+                WebsocketServerEvent::OnMessage(_, msg) => Some(msg.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn limits_exceeded(server_events: &[WebsocketServerEvent]) -> Vec<Limit> {
+        server_events
+            .iter()
+            .filter_map(|event| match event {
+                WebsocketServerEvent::LimitExceeded(_, limit) => Some(*limit),
+                _ => None,
+            })
+            .collect()
+    }
+
+    struct LastDiagnostics(Arc<Mutex<Option<ServerDiagnostics>>>);
+
+    // keeps the first diagnostics counting every expected message, then sends the client away
+    fn record_diagnostics_and_disconnect(
+        ws: Res<WebsocketServerResource>,
+        mut diagnostics: EventReader<ServerDiagnostics>,
+        last: Res<LastDiagnostics>,
+        expected: Res<ExpectedMessages>,
+        mut exit: EventWriter<AppExit>,
+    ) {
+        for diagnostics in diagnostics.iter() {
+            let done = diagnostics
+                .clients
+                .values()
+                .any(|client| client.messages_in == expected.0 as u64);
+            if done && last.0.lock().unwrap().is_none() {
+                for client_id in diagnostics.clients.keys() {
+                    ws.disconnect(*client_id, "done");
+                }
+                last.0.lock().unwrap().replace(diagnostics.clone());
+                exit.send(AppExit);
+            }
+        }
+    }
+
+    fn startup_server_with_one_slot(
+        mut ws: ResMut<WebsocketServerResource>,
+        overflow: Res<Overflow>,
+        port: Res<PortResource>,
+    ) {
+        ws.set_max_clients(Some(1), *overflow);
+        ws.listen(format!("localhost:{}", port.0).as_str());
+    }
+
+    fn exit_once_player_and_spectator_joined(
+        ws: Res<WebsocketServerResource>,
+        connection_count: Res<ConnectionCount>,
+        mut exit: EventWriter<AppExit>,
+    ) {
+        let expected = ConnectionCount {
+            connections: 2,
+            players: 1,
+            spectators: 1,
+        };
+        if *connection_count == expected {
+            let spectators = ws
+                .clients()
+                .filter(|(client_id, _)| ws.is_spectator(*client_id))
+                .count();
+            assert_eq!(spectators, 1);
+            exit.send(AppExit);
+        }
+    }
+
+    struct RemoteAddresses(Arc<Mutex<Vec<Option<IpAddr>>>>);
+
+    fn record_remote_addresses(
+        ws: Res<WebsocketServerResource>,
+        mut ws_events: EventReader<WebsocketServerEvent>,
+        remote_addresses: Res<RemoteAddresses>,
+    ) {
+        for event in ws_events.iter() {
+            if let WebsocketServerEvent::OnOpen(client_id) = event {
+                let client = ws.client(*client_id).unwrap();
+                assert_eq!(
+                    client.peer_addr.map(|addr| addr.ip()),
+                    Some(IpAddr::from([127, 0, 0, 1]))
+                );
+                assert_eq!(client.headers.get("sec-websocket-version").unwrap(), "13");
+                remote_addresses.0.lock().unwrap().push(client.remote_addr);
+            }
+        }
+    }
+
+    fn startup_server_on_ipv4(mut ws: ResMut<WebsocketServerResource>, port: Res<PortResource>) {
+        ws.listen(format!("127.0.0.1:{}", port.0).as_str());
+    }
+
+    fn fail_when_connected(mut ws_events: EventReader<WebsocketClientEvent>) {
+        for event in ws_events.iter() {
+            if let WebsocketClientEvent::OnOpen(_) = event {
+                panic!("connected to a server with an untrusted certificate");
             }
         }
     }
@@ -184,8 +855,8 @@ mod tests {
             App::new()
                 .add_plugins(MinimalPlugins)
                 .add_plugin(WebsocketServerPlugin)
-                .add_startup_system(startup_server.system())
-                .add_system(network_bounce_server.system())
+                .add_startup_system(startup_server)
+                .add_system(network_bounce_server)
                 .insert_resource(NameResource("Server".to_string()))
                 .run();
             info!("Server thread done");
@@ -205,8 +876,8 @@ mod tests {
             App::new()
                 .add_plugins(MinimalPlugins)
                 .add_plugin(WebsocketPlugin)
-                .add_startup_system(startup_client.system())
-                .add_system(network_bounce_client_single.system())
+                .add_startup_system(startup_client)
+                .add_system(network_bounce_client_single)
                 .insert_resource(NameResource("Client 1".to_string()))
                 .run();
             info!("Client 1 thread done");
@@ -225,8 +896,8 @@ mod tests {
             App::new()
                 .add_plugins(MinimalPlugins)
                 .add_plugin(WebsocketServerPlugin)
-                .add_startup_system(startup_server.system())
-                .add_system(network_bounce_server.system())
+                .add_startup_system(startup_server)
+                .add_system(network_bounce_server)
                 .insert_resource(NameResource("Server".to_string()))
                 .run();
             info!("Server thread done");
@@ -241,79 +912,1287 @@ mod tests {
             .filter_level(LevelFilter::Info)
             .try_init();
 
-        let server = std::thread::spawn(move || {
-            App::new()
-                .add_plugins(MinimalPlugins)
-                .add_plugin(WebsocketServerPlugin)
-                .add_startup_system(startup_server.system())
-                .add_system(network_bounce_server.system())
-                .insert_resource(NameResource("Server".to_string()))
-                .insert_resource(PortResource(8080))
-                .run();
-            info!("Server thread done");
-        });
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system(startup_server)
+            .add_system(network_bounce_server)
+            .insert_resource(NameResource("Server".to_string()))
+            .insert_resource(PortResource(0));
+        update_until_listening(&mut server);
+        let port = listening_port(&server);
 
-        let client = std::thread::spawn(move || {
-            App::new()
+        let mut client2 = App::new();
+        client2
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin)
+            .add_startup_system(startup_client)
+            .add_system(network_bounce_client_multiple)
+            .insert_resource(NameResource("Client 2".to_string()))
+            .insert_resource(PortResource(port));
+        // client 1 starts counting as soon as it connects, so client 2 has to be there first
+        for _ in 0..5000 {
+            server.update();
+            client2.update();
+            if server.world.resource::<ConnectionCount>().players == 1 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut client = App::new();
+        client
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin)
+            .add_startup_system(startup_client)
+            .add_system(network_bounce_client_multiple)
+            .insert_resource(NameResource("Client 1".to_string()))
+            .insert_resource(PortResource(port));
+
+        run_until_exit(vec![server, client2, client]);
+    }
+
+    #[test]
+    // #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    fn one_client_one_server_count_to_ten() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system(startup_server)
+            .add_system(network_bounce_server)
+            .insert_resource(NameResource("Server".to_string()))
+            .insert_resource(PortResource(0));
+        update_until_listening(&mut server);
+        let port = listening_port(&server);
+
+        let mut client = App::new();
+        client
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin)
+            .add_startup_system(startup_client)
+            .add_system(network_bounce_client_single)
+            .insert_resource(NameResource("Client 1".to_string()))
+            .insert_resource(PortResource(port));
+
+        run_until_exit(vec![server, client]);
+    }
+
+    #[test]
+    fn shutdown_closes_clients_before_stopping() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+
+        let loopback = LoopbackTransport::default();
+
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_server)
+            .add_startup_system(startup_server)
+            .add_system(shutdown_server_when_client_connects)
+            .insert_resource(Loopback(loopback.clone()))
+            .insert_resource(PortResource(8080));
+        update_until_listening(&mut server);
+
+        let mut client = App::new();
+        client
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_client)
+            .add_startup_system(startup_client)
+            .add_system(exit_when_server_closes)
+            .insert_resource(Loopback(loopback))
+            .insert_resource(PortResource(8080));
+
+        run_until_exit(vec![server, client]);
+    }
+
+    #[test]
+    fn server_can_listen_again_on_another_port() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system(startup_server)
+            .add_system(move_server_when_first_client_connects)
+            .insert_resource(PortResource(0));
+        update_until_listening(&mut server);
+        let first_port = listening_port(&server);
+
+        // the first client is sent away when the server moves
+        let mut client = App::new();
+        client
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin)
+            .add_startup_system(startup_client)
+            .add_system(exit_when_server_closes)
+            .insert_resource(PortResource(first_port));
+        update_until_exit(&mut client, &mut server);
+        update_until(&mut server, |ws| {
+            ws.is_listening() && ws.local_addr().map(|addr| addr.port().into()) != Some(first_port)
+        });
+
+        let mut client2 = App::new();
+        client2
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin)
+            .add_startup_system(startup_client)
+            .add_system(exit_when_connected)
+            .insert_resource(PortResource(listening_port(&server)));
+
+        run_until_exit(vec![server, client2]);
+    }
+
+    #[test]
+    fn two_clients_one_server_count_to_ten_over_loopback() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let loopback = LoopbackTransport::default();
+
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_server)
+            .add_startup_system(startup_server)
+            .add_system(network_bounce_server)
+            .insert_resource(Loopback(loopback.clone()))
+            .insert_resource(PortResource(8080));
+
+        let clients = ["Client 1", "Client 2"].map(|name| {
+            let mut client = App::new();
+            client
                 .add_plugins(MinimalPlugins)
                 .add_plugin(WebsocketPlugin)
-                .add_startup_system(startup_client.system())
-                .add_system(network_bounce_client_multiple.system())
-                .insert_resource(NameResource("Client 1".to_string()))
-                .insert_resource(PortResource(8080))
-                .run();
-            info!("Client 1 thread done");
+                .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_client)
+                .add_startup_system(startup_client)
+                .add_system(network_bounce_client_multiple)
+                .insert_resource(NameResource(name.to_string()))
+                .insert_resource(Loopback(loopback.clone()))
+                .insert_resource(PortResource(8080));
+            client
         });
 
-        let client2 = std::thread::spawn(move || {
-            App::new()
+        // the server goes first so it's listening by the time the clients connect
+        let mut apps = vec![server];
+        apps.extend(clients);
+        run_until_exit(apps);
+    }
+
+    #[test]
+    fn two_clients_one_server_count_to_ten_over_slow_loopback() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let loopback = LoopbackTransport::default();
+        let conditions = NetworkConditions {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(5),
+            seed: Some(1),
+            ..Default::default()
+        };
+
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_conditioned_loopback_server)
+            .add_startup_system(startup_server)
+            .add_system(network_bounce_server)
+            .insert_resource(Loopback(loopback.clone()))
+            .insert_resource(Conditions(conditions.clone()))
+            .insert_resource(PortResource(8080));
+
+        let clients = ["Client 1", "Client 2"].map(|name| {
+            let mut client = App::new();
+            client
                 .add_plugins(MinimalPlugins)
                 .add_plugin(WebsocketPlugin)
-                .add_startup_system(startup_client.system())
-                .add_system(network_bounce_client_multiple.system())
-                .insert_resource(NameResource("Client 2".to_string()))
-                .insert_resource(PortResource(8080))
-                .run();
-            info!("Client 2 thread done");
+                .add_startup_system_to_stage(
+                    StartupStage::PreStartup,
+                    use_conditioned_loopback_client,
+                )
+                .add_startup_system(startup_client)
+                .add_system(network_bounce_client_multiple)
+                .insert_resource(NameResource(name.to_string()))
+                .insert_resource(Loopback(loopback.clone()))
+                .insert_resource(Conditions(conditions.clone()))
+                .insert_resource(PortResource(8080));
+            client
         });
 
-        server.join().unwrap();
-        client.join().unwrap();
-        client2.join().unwrap();
+        let mut apps = vec![server];
+        apps.extend(clients);
+        run_until_exit(apps);
     }
 
     #[test]
-    // #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
-    fn one_client_one_server_count_to_ten() {
+    fn conditioner_delays_messages_in_order() {
+        let started = Instant::now();
+        let received = send_conditioned(
+            NetworkConditions {
+                latency: Duration::from_millis(100),
+                ..Default::default()
+            },
+            10,
+        );
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        let expected: Vec<String> = (0..10).map(|i| i.to_string()).collect();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn conditioner_drops_lost_messages() {
+        let received = send_conditioned(
+            NetworkConditions {
+                loss: 1.0,
+                ..Default::default()
+            },
+            10,
+        );
+        assert!(received.is_empty());
+
+        let received = send_conditioned(
+            NetworkConditions {
+                loss: 0.5,
+                ..Default::default()
+            },
+            100,
+        );
+        assert!(!received.is_empty() && received.len() < 100);
+    }
+
+    #[test]
+    fn conditioner_duplicates_messages() {
+        let received = send_conditioned(
+            NetworkConditions {
+                duplicate: 1.0,
+                ..Default::default()
+            },
+            10,
+        );
+        let expected: Vec<String> = (0..10)
+            .flat_map(|i| [i.to_string(), i.to_string()])
+            .collect();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn conditioner_reorders_messages() {
+        let received = send_conditioned(
+            NetworkConditions {
+                reorder: 0.5,
+                ..Default::default()
+            },
+            20,
+        );
+        let mut sorted = received.clone();
+        sorted.sort_by_key(|msg| msg.parse::<u32>().unwrap());
+        let expected: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+        assert_eq!(sorted, expected);
+        assert_ne!(received, expected);
+    }
+
+    #[test]
+    fn conditioner_clamps_chances() {
+        let received = send_conditioned(
+            NetworkConditions {
+                loss: 2.0,
+                ..Default::default()
+            },
+            10,
+        );
+        assert!(received.is_empty());
+
+        let received = send_conditioned(
+            NetworkConditions {
+                loss: -1.0,
+                duplicate: f64::NAN,
+                reorder: f64::INFINITY,
+                ..Default::default()
+            },
+            10,
+        );
+        let expected: Vec<String> = (0..10).map(|i| i.to_string()).collect();
+        assert_eq!(received, expected);
+    }
+
+    // reads `items` and swallows whatever is sent
+    struct Scripted(futures::stream::Iter<std::vec::IntoIter<Result<WsMessage, WsError>>>);
+
+    impl futures::Stream for Scripted {
+        type Item = Result<WsMessage, WsError>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.0.poll_next_unpin(cx)
+        }
+    }
+
+    impl futures::Sink<WsMessage> for Scripted {
+        type Error = WsError;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+        fn start_send(self: Pin<&mut Self>, _item: WsMessage) -> Result<(), WsError> {
+            Ok(())
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn conditioner_passes_errors_on() {
+        let task_pool = IoTaskPool(TaskPool::new());
+        let too_long = CapacityError::MessageTooLong {
+            size: 100,
+            max_size: 8,
+        };
+        let scripted = Scripted(futures::stream::iter(vec![
+            Ok(WsMessage::Text("before".to_string())),
+            Err(WsError::Capacity(too_long)),
+            Ok(WsMessage::Text("after".to_string())),
+        ]));
+        let conditioned = condition(
+            Box::pin(scripted),
+            NetworkConditions::default(),
+            StdRng::seed_from_u64(7),
+            &task_pool,
+        );
+        let received: Vec<_> = futures::executor::block_on(conditioned.collect());
+        assert!(matches!(&received[..], [
+            Ok(WsMessage::Text(before)),
+            Err(WsError::Capacity(CapacityError::MessageTooLong { size: 100, .. })),
+            Ok(WsMessage::Text(after)),
+        ] if before == "before" && after == "after"));
+    }
+
+    #[test]
+    fn network_profiles_parse_by_name() {
+        for profile in NetworkConditions::PROFILES {
+            assert!(profile.parse::<NetworkConditions>().is_ok(), "{}", profile);
+        }
+        assert_eq!("mobile".parse().ok(), Some(NetworkConditions::mobile()));
+        assert!("dial-up".parse::<NetworkConditions>().is_err());
+    }
+
+    #[test]
+    fn one_client_one_server_count_to_ten_over_tls() {
         let _res = env_logger::builder()
             .filter_level(LevelFilter::Info)
             .try_init();
 
-        let server = std::thread::spawn(move || {
-            App::new()
-                .add_plugins(MinimalPlugins)
-                .add_plugin(WebsocketServerPlugin)
-                .add_startup_system(startup_server.system())
-                .add_system(network_bounce_server.system())
-                .insert_resource(NameResource("Server".to_string()))
-                .insert_resource(PortResource(8081))
-                .run();
-            info!("Server thread done");
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system(startup_tls_server)
+            .add_system(network_bounce_server)
+            .insert_resource(PortResource(0));
+        update_until_listening(&mut server);
+        let port = listening_port(&server);
+
+        let mut client = App::new();
+        client
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin)
+            .add_startup_system(startup_tls_client)
+            .add_system(network_bounce_client_single)
+            .insert_resource(NameResource("Client 1".to_string()))
+            .insert_resource(PortResource(port));
+
+        run_until_exit(vec![server, client]);
+    }
+
+    #[test]
+    fn client_rejects_untrusted_certificate() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system(startup_tls_server)
+            .insert_resource(PortResource(0));
+        update_until_listening(&mut server);
+        let port = listening_port(&server);
+
+        let mut client = App::new();
+        client
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin)
+            .add_startup_system(startup_untrusting_tls_client)
+            .add_system(exit_when_server_closes)
+            .add_system(fail_when_connected)
+            .insert_resource(PortResource(port));
+        run_until_exit(vec![client]);
+    }
+
+    #[test]
+    fn authenticated_client_gets_identity() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let loopback = LoopbackTransport::default();
+
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_server)
+            .add_startup_system(startup_authenticating_server)
+            .add_system(exit_when_alice_connects)
+            .insert_resource(Loopback(loopback.clone()))
+            .insert_resource(PortResource(8080));
+
+        let mut client = App::new();
+        client
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_client)
+            .add_startup_system(startup_client_with_token)
+            .add_system(exit_when_connected)
+            .insert_resource(Loopback(loopback))
+            .insert_resource(PortResource(8080));
+
+        run_until_exit(vec![server, client]);
+    }
+
+    #[test]
+    fn upgrade_is_rejected_with_http_status() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        // upgrades are answered by the listener, the server only has to get it going
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system(startup_authenticating_server)
+            .insert_resource(PortResource(0));
+        update_until_listening(&mut server);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let status = |request: Request<()>| match runtime
+            .block_on(tokio_tungstenite::connect_async(request))
+        {
+            Ok(_) => StatusCode::SWITCHING_PROTOCOLS,
+            Err(WsError::Http(response)) => response.status(),
+            Err(e) => panic!("unexpected error {:?}", e),
+        };
+        let url = format!("ws://localhost:{}/", listening_port(&server));
+        assert_eq!(
+            status(url.as_str().into_client_request().unwrap()),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(
+                format!("{}?token=guess", url)
+                    .into_client_request()
+                    .unwrap()
+            ),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(
+                format!("{}?token=secret", url)
+                    .into_client_request()
+                    .unwrap()
+            ),
+            StatusCode::SWITCHING_PROTOCOLS
+        );
+        let mut with_cookie = url.as_str().into_client_request().unwrap();
+        with_cookie
+            .headers_mut()
+            .insert("cookie", "theme=dark; token=secret".parse().unwrap());
+        assert_eq!(status(with_cookie), StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[test]
+    fn upgrade_from_disallowed_origin_is_forbidden() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let mut server = App::new();
+        server
+            .insert_resource(WebsocketServerSettings {
+                allowed_origins: vec!["https://pong.example".to_string()],
+                ..Default::default()
+            })
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system(startup_server)
+            .insert_resource(PortResource(0));
+        update_until_listening(&mut server);
+        let url = format!("ws://localhost:{}/", listening_port(&server));
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let status = |origin: Option<&str>| {
+            let mut request = url.as_str().into_client_request().unwrap();
+            if let Some(origin) = origin {
+                request
+                    .headers_mut()
+                    .insert("origin", origin.parse().unwrap());
+            }
+            match runtime.block_on(tokio_tungstenite::connect_async(request)) {
+                Ok(_) => StatusCode::SWITCHING_PROTOCOLS,
+                Err(WsError::Http(response)) => response.status(),
+                Err(e) => panic!("unexpected error {:?}", e),
+            }
+        };
+        assert_eq!(
+            status(Some("https://pong.example")),
+            StatusCode::SWITCHING_PROTOCOLS
+        );
+        assert_eq!(
+            status(Some("HTTPS://Pong.Example")),
+            StatusCode::SWITCHING_PROTOCOLS
+        );
+        assert_eq!(status(Some("https://evil.example")), StatusCode::FORBIDDEN);
+        assert_eq!(status(Some("null")), StatusCode::FORBIDDEN);
+        // native clients don't send an origin
+        assert_eq!(status(None), StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[test]
+    fn static_files_are_served_next_to_the_websocket() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let root = std::env::temp_dir().join("bevy_ws_static_files");
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        std::fs::write(root.join("index.html"), "<html></html>").unwrap();
+        std::fs::write(root.join("assets").join("ball.png"), [1, 2, 3]).unwrap();
+        let mut server = App::new();
+        server
+            .insert_resource(WebsocketServerSettings {
+                static_files: Some(StaticFiles {
+                    root,
+                    websocket_path: "/ws".to_string(),
+                }),
+                ..Default::default()
+            })
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system(startup_server)
+            .insert_resource(PortResource(0));
+        update_until_listening(&mut server);
+        let address = format!("localhost:{}", listening_port(&server));
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let get = |path: &str| {
+            runtime.block_on(async {
+                let mut stream = tokio::net::TcpStream::connect(&address).await.unwrap();
+                let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+                stream.write_all(request.as_bytes()).await.unwrap();
+                let mut response = vec![];
+                stream.read_to_end(&mut response).await.unwrap();
+                String::from_utf8_lossy(&response).into_owned()
+            })
+        };
+        let index = get("/");
+        assert!(index.starts_with("HTTP/1.1 200 OK"), "{}", index);
+        assert!(index.contains("text/html"), "{}", index);
+        assert!(index.ends_with("<html></html>"), "{}", index);
+        let image = get("/assets/ball.png?v=1");
+        assert!(image.starts_with("HTTP/1.1 200 OK"), "{}", image);
+        assert!(image.contains("image/png"), "{}", image);
+        assert!(get("/missing.js").starts_with("HTTP/1.1 404"));
+        assert!(get("/../bevy_ws_static_files/index.html").starts_with("HTTP/1.1 404"));
+
+        let status = |path: &str| {
+            let url = format!("ws://{}{}", address, path);
+            match runtime.block_on(tokio_tungstenite::connect_async(url)) {
+                Ok(_) => StatusCode::SWITCHING_PROTOCOLS,
+                Err(WsError::Http(response)) => response.status(),
+                Err(e) => panic!("unexpected error {:?}", e),
+            }
+        };
+        assert_eq!(status("/ws?session=abc"), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(status("/"), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn static_files_are_served_at_max_connections() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let root = std::env::temp_dir().join("bevy_ws_static_files_full");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "<html></html>").unwrap();
+        let mut server = App::new();
+        server
+            .insert_resource(WebsocketServerSettings {
+                max_connections: Some(0),
+                static_files: Some(StaticFiles {
+                    root,
+                    websocket_path: "/ws".to_string(),
+                }),
+                ..Default::default()
+            })
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system(startup_server)
+            .insert_resource(PortResource(0));
+        update_until_listening(&mut server);
+        let address = format!("localhost:{}", listening_port(&server));
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let index = runtime.block_on(async {
+            let mut stream = tokio::net::TcpStream::connect(&address).await.unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = vec![];
+            stream.read_to_end(&mut response).await.unwrap();
+            String::from_utf8_lossy(&response).into_owned()
         });
+        assert!(index.starts_with("HTTP/1.1 200 OK"), "{}", index);
+        assert!(runtime
+            .block_on(tokio_tungstenite::connect_async(format!(
+                "ws://{}/ws",
+                address
+            )))
+            .is_err());
+    }
 
-        let client = std::thread::spawn(move || {
-            App::new()
+    #[test]
+    fn upgrades_over_max_connections_are_unavailable() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let root = std::env::temp_dir().join("bevy_ws_static_files_unavailable");
+        std::fs::create_dir_all(&root).unwrap();
+        let mut server = App::new();
+        server
+            .insert_resource(WebsocketServerSettings {
+                max_connections: Some(0),
+                static_files: Some(StaticFiles {
+                    root,
+                    websocket_path: "/ws".to_string(),
+                }),
+                ..Default::default()
+            })
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system(startup_server)
+            .insert_resource(PortResource(0));
+        update_until_listening(&mut server);
+        let url = format!("ws://localhost:{}/ws", listening_port(&server));
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        match runtime.block_on(tokio_tungstenite::connect_async(&url)) {
+            Err(WsError::Http(response)) => {
+                assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            }
+            other => panic!(
+                "expected 503, got {:?}",
+                other.map(|(_, response)| response)
+            ),
+        }
+    }
+
+    #[test]
+    fn session_tokens_only_resume_once() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let mut server = App::new();
+        server
+            .insert_resource(WebsocketServerSettings {
+                session_grace_period: Duration::from_secs(10),
+                ..Default::default()
+            })
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system(startup_server)
+            .insert_resource(PortResource(0));
+        update_until_listening(&mut server);
+        let url = format!("ws://localhost:{}", listening_port(&server));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _context = runtime.enter();
+
+        // connects offering `token` and steps the server until the welcome message arrives
+        let connect = |server: &mut App, token: Option<&str>| {
+            let mut request = url.as_str().into_client_request().unwrap();
+            let protocol = token.map(|token| format!("bevy_ws.session.{}", token));
+            if let Some(protocol) = &protocol {
+                request
+                    .headers_mut()
+                    .insert("sec-websocket-protocol", protocol.parse().unwrap());
+            }
+            let (mut connection, response) = runtime
+                .block_on(tokio_tungstenite::connect_async(request))
+                .unwrap();
+            // browsers drop the connection unless the server agrees to the subprotocol
+            let echoed = response
+                .headers()
+                .get("sec-websocket-protocol")
+                .map(|value| value.to_str().unwrap().to_string());
+            assert_eq!(echoed, protocol);
+            for _ in 0..1000 {
+                server.update();
+                if let Some(Some(Ok(WsMessage::Text(welcome)))) = connection.next().now_or_never() {
+                    let mut split = welcome.split_whitespace().skip(1);
+                    let client_id: u64 = split.next().unwrap().parse().unwrap();
+                    let token = split.next().unwrap().to_string();
+                    return (client_id, token, connection);
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            panic!("no welcome message");
+        };
+        let drop_connection = |server: &mut App, client_id: u64, connection| {
+            drop(connection);
+            for _ in 0..1000 {
+                server.update();
+                let ws = server.world.resource::<WebsocketServerResource>();
+                if ws.is_suspended(client_id) {
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            panic!("client {} was never suspended", client_id);
+        };
+
+        let (client_id, first_token, connection) = connect(&mut server, None);
+        drop_connection(&mut server, client_id, connection);
+        let (resumed_id, second_token, connection) = connect(&mut server, Some(&first_token));
+        assert_eq!(resumed_id, client_id);
+        assert_ne!(second_token, first_token);
+        let ws = server.world.resource::<WebsocketServerResource>();
+        assert!(!ws
+            .client(client_id)
+            .unwrap()
+            .uri
+            .to_string()
+            .contains(&second_token));
+
+        drop_connection(&mut server, client_id, connection);
+        let (replayed_id, _, _connection) = connect(&mut server, Some(&first_token));
+        assert_ne!(replayed_id, client_id);
+        let ws = server.world.resource::<WebsocketServerResource>();
+        assert!(ws.is_suspended(client_id));
+    }
+
+    #[test]
+    fn clients_carry_metadata_and_user_data() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let loopback = LoopbackTransport::default();
+
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_server)
+            .add_startup_system(startup_server)
+            .add_system(name_clients_and_exit_on_message)
+            .insert_resource(Loopback(loopback.clone()))
+            .insert_resource(PortResource(8080));
+
+        let mut client = App::new();
+        client
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_client)
+            .add_startup_system(startup_client_in_room)
+            .add_system(send_hello_and_exit)
+            .insert_resource(Loopback(loopback))
+            .insert_resource(PortResource(8080));
+
+        run_until_exit(vec![server, client]);
+    }
+
+    #[test]
+    fn clients_are_found_by_session_token() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let loopback = LoopbackTransport::default();
+
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_server)
+            .add_startup_system(startup_server)
+            .add_system(reply_to_client_with_matching_token)
+            .insert_resource(Loopback(loopback.clone()))
+            .insert_resource(PortResource(8080));
+
+        let mut client = App::new();
+        client
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_client)
+            .add_startup_system(startup_client)
+            .add_system(send_session_token_and_exit_on_reply)
+            .insert_resource(Loopback(loopback))
+            .insert_resource(PortResource(8080));
+
+        run_until_exit(vec![server, client]);
+    }
+
+    #[test]
+    fn client_resumes_session_within_grace_period() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let (server_events, client_ids) = drop_and_resume(
+            Duration::from_secs(10),
+            ResumeAfter(|event| matches!(event, WebsocketServerEvent::OnDisconnect(_))),
+        );
+
+        assert_eq!(client_ids.len(), 2);
+        assert_eq!(client_ids[0], client_ids[1]);
+        let id = client_ids[0];
+        assert!(matches!(
+            server_events.as_slice(),
+            [
+                WebsocketServerEvent::OnOpen(open),
+                WebsocketServerEvent::OnDisconnect(disconnect),
+                WebsocketServerEvent::OnResume(resume),
+            ] if [*open, *disconnect, *resume] == [id, id, id]
+        ));
+    }
+
+    #[test]
+    fn session_expires_after_grace_period() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let (server_events, client_ids) = drop_and_resume(
+            Duration::from_millis(1),
+            ResumeAfter(|event| matches!(event, WebsocketServerEvent::OnClose(_))),
+        );
+
+        assert_eq!(client_ids.len(), 2);
+        assert_ne!(client_ids[0], client_ids[1]);
+        let (first, second) = (client_ids[0], client_ids[1]);
+        assert!(matches!(
+            server_events.as_slice(),
+            [
+                WebsocketServerEvent::OnOpen(open),
+                WebsocketServerEvent::OnDisconnect(disconnect),
+                WebsocketServerEvent::OnClose(close),
+                WebsocketServerEvent::OnOpen(reopen),
+            ] if [*open, *disconnect, *close, *reopen] == [first, first, first, second]
+        ));
+    }
+
+    #[test]
+    fn messages_over_rate_limit_are_dropped() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let settings = WebsocketServerSettings {
+            rate_limit: Some(RateLimit {
+                per_second: 0.001,
+                burst: 3,
+            }),
+            ..Default::default()
+        };
+        let burst = Burst {
+            messages: vec!["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"],
+            wait_for_close: false,
+        };
+        let server_events = send_burst(settings, burst, 10);
+
+        assert_eq!(received(&server_events), vec!["0", "1", "2"]);
+        assert_eq!(limits_exceeded(&server_events), vec![Limit::Rate; 7]);
+    }
+
+    #[test]
+    fn messages_over_size_limit_are_dropped() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let settings = WebsocketServerSettings {
+            max_message_size: Some(8),
+            ..Default::default()
+        };
+        let burst = Burst {
+            messages: vec!["far too long", "short"],
+            wait_for_close: false,
+        };
+        let server_events = send_burst(settings, burst, 2);
+
+        assert_eq!(received(&server_events), vec!["short"]);
+        assert_eq!(limits_exceeded(&server_events), vec![Limit::MessageSize]);
+    }
+
+    #[test]
+    fn repeat_offenders_are_disconnected_for_good() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let settings = WebsocketServerSettings {
+            rate_limit: Some(RateLimit {
+                per_second: 0.001,
+                burst: 1,
+            }),
+            max_violations: Some(2),
+            session_grace_period: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let burst = Burst {
+            messages: vec!["0", "1", "2", "3", "4"],
+            wait_for_close: true,
+        };
+        let server_events = send_burst(settings, burst, 5);
+
+        assert_eq!(received(&server_events), vec!["0"]);
+        assert_eq!(limits_exceeded(&server_events), vec![Limit::Rate; 2]);
+        assert!(matches!(
+            server_events.last(),
+            Some(WebsocketServerEvent::OnClose(_))
+        ));
+    }
+
+    #[test]
+    fn traffic_is_counted_per_client_and_in_total() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let loopback = LoopbackTransport::default();
+        let last: Arc<Mutex<Option<ServerDiagnostics>>> = Default::default();
+
+        let mut server = App::new();
+        server
+            .insert_resource(WebsocketServerSettings {
+                rate_limit: Some(RateLimit {
+                    per_second: 0.001,
+                    burst: 3,
+                }),
+                diagnostics_interval: Some(Duration::ZERO),
+                ..Default::default()
+            })
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_server)
+            .add_startup_system(startup_server)
+            .add_system(record_diagnostics_and_disconnect)
+            .insert_resource(LastDiagnostics(last.clone()))
+            .insert_resource(ExpectedMessages(5))
+            .insert_resource(Loopback(loopback.clone()))
+            .insert_resource(PortResource(8080));
+
+        let mut client = App::new();
+        client
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_client)
+            .add_startup_system(startup_client)
+            .add_system(send_burst_and_exit)
+            .insert_resource(Burst {
+                messages: vec!["0", "1", "2", "3", "4"],
+                wait_for_close: true,
+            })
+            .insert_resource(Loopback(loopback))
+            .insert_resource(PortResource(8080));
+
+        run_until_exit(vec![server, client]);
+
+        let diagnostics = last.lock().unwrap().take().unwrap();
+        let client = *diagnostics.clients.values().next().unwrap();
+        assert_eq!(diagnostics.clients.len(), 1);
+        assert_eq!(client.messages_in, 5);
+        assert_eq!(client.bytes_in, 5);
+        assert_eq!(client.dropped, 2);
+        // the welcome message
+        assert_eq!(client.messages_out, 1);
+        assert!(client.bytes_out > 0);
+        assert_eq!(client.queued, 0);
+        assert_eq!(diagnostics.total, client);
+    }
+
+    #[test]
+    fn clients_over_the_cap_are_rejected_as_server_full() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let loopback = LoopbackTransport::default();
+        let task_pool = IoTaskPool(TaskPool::new());
+
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_server)
+            .add_startup_system(startup_server_with_one_slot)
+            .insert_resource(Overflow::Reject)
+            .insert_resource(Loopback(loopback.clone()))
+            .insert_resource(PortResource(8080));
+        server.update();
+        server.update();
+
+        let connect = |loopback: &LoopbackTransport| {
+            futures::executor::block_on(loopback.connect(
+                "ws://localhost:8080".into_client_request().unwrap(),
+                WebsocketSettings::default(),
+                task_pool.clone(),
+            ))
+            .unwrap()
+        };
+        // steps the server until `connection` gets something
+        let mut next_message = |connection: &mut Connection| {
+            for _ in 0..1000 {
+                server.update();
+                if let Some(msg) = connection.next().now_or_never() {
+                    return msg;
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            panic!("nothing arrived");
+        };
+
+        let mut player = connect(&loopback);
+        assert!(matches!(
+            next_message(&mut player),
+            Some(Ok(WsMessage::Text(_)))
+        ));
+        let mut rejected = connect(&loopback);
+        match next_message(&mut rejected) {
+            Some(Ok(WsMessage::Close(Some(frame)))) => {
+                assert_eq!(frame.code, CloseCode::Again);
+                assert_eq!(frame.reason, SERVER_FULL);
+            }
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        server.update();
+        assert_eq!(
+            *server.world.resource::<ConnectionCount>(),
+            ConnectionCount {
+                connections: 1,
+                players: 1,
+                spectators: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn clients_over_the_cap_can_spectate() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let loopback = LoopbackTransport::default();
+
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_server)
+            .add_startup_system(startup_server_with_one_slot)
+            .add_system(exit_once_player_and_spectator_joined)
+            .insert_resource(Overflow::Spectate)
+            .insert_resource(Loopback(loopback.clone()))
+            .insert_resource(PortResource(8080));
+
+        let clients = [0, 1].map(|_| {
+            let mut client = App::new();
+            client
                 .add_plugins(MinimalPlugins)
                 .add_plugin(WebsocketPlugin)
-                .add_startup_system(startup_client.system())
-                .add_system(network_bounce_client_single.system())
-                .insert_resource(NameResource("Client 1".to_string()))
-                .insert_resource(PortResource(8081))
-                .run();
-            info!("Client 1 thread done");
+                .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_client)
+                .add_startup_system(startup_client)
+                .add_system(exit_when_connected)
+                .insert_resource(Loopback(loopback.clone()))
+                .insert_resource(PortResource(8080));
+            client
         });
 
-        server.join().unwrap();
-        client.join().unwrap();
+        let mut apps = vec![server];
+        apps.extend(clients);
+        run_until_exit(apps);
+    }
+
+    #[test]
+    fn group_broadcasts_only_reach_members() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let loopback = LoopbackTransport::default();
+        let task_pool = IoTaskPool(TaskPool::new());
+
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_server)
+            .add_startup_system(startup_server)
+            .insert_resource(Loopback(loopback.clone()))
+            .insert_resource(PortResource(8080));
+        server.update();
+        server.update();
+
+        // steps the server until `connection` gets some text
+        let next_text = |server: &mut App, connection: &mut Connection| {
+            for _ in 0..1000 {
+                server.update();
+                if let Some(Some(Ok(WsMessage::Text(text)))) = connection.next().now_or_never() {
+                    return text;
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            panic!("nothing arrived");
+        };
+        let connect = |server: &mut App| {
+            let mut connection = futures::executor::block_on(loopback.connect(
+                "ws://localhost:8080".into_client_request().unwrap(),
+                WebsocketSettings::default(),
+                task_pool.clone(),
+            ))
+            .unwrap();
+            let welcome = next_text(server, &mut connection);
+            let client_id: u64 = welcome.split_whitespace().nth(1).unwrap().parse().unwrap();
+            (client_id, connection)
+        };
+        let (alice, mut alice_connection) = connect(&mut server);
+        let (bob, mut bob_connection) = connect(&mut server);
+        let (carol, mut carol_connection) = connect(&mut server);
+
+        let mut ws = server.world.resource_mut::<WebsocketServerResource>();
+        assert!(ws.join_group("red", alice));
+        assert!(ws.join_group("red", bob));
+        assert!(!ws.join_group("red", bob));
+        assert!(ws.join_group("blue", bob));
+        assert!(ws.join_group("blue", carol));
+        assert!(!ws.join_group("blue", 0));
+        assert!(ws.leave_group("blue", carol));
+        assert!(!ws.leave_group("blue", carol));
+        let mut groups: Vec<_> = ws.groups_of(bob).collect();
+        groups.sort();
+        assert_eq!(groups, vec!["blue", "red"]);
+        ws.broadcast_to_group("red", "hello red".to_string());
+        ws.broadcast_to_group("blue", "hello blue".to_string());
+
+        assert_eq!(next_text(&mut server, &mut alice_connection), "hello red");
+        assert_eq!(next_text(&mut server, &mut bob_connection), "hello red");
+        assert_eq!(next_text(&mut server, &mut bob_connection), "hello blue");
+        server.update();
+        assert!(carol_connection.next().now_or_never().is_none());
+
+        futures::executor::block_on(alice_connection.send(WsMessage::Close(None))).unwrap();
+        for _ in 0..1000 {
+            server.update();
+            let ws = server.world.resource::<WebsocketServerResource>();
+            if ws.group_members("red").eq([bob]) {
+                assert_eq!(ws.groups_of(alice).count(), 0);
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("alice is still in a group after closing");
+    }
+
+    #[test]
+    fn groups_are_forgotten_when_the_server_restarts() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let loopback = LoopbackTransport::default();
+        let task_pool = IoTaskPool(TaskPool::new());
+
+        let mut server = App::new();
+        server
+            // the client never answers the close, no need to wait long for it
+            .insert_resource(WebsocketServerSettings {
+                shutdown_timeout: Duration::from_millis(100),
+                ..Default::default()
+            })
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system_to_stage(StartupStage::PreStartup, use_loopback_server)
+            .add_startup_system(startup_server)
+            .insert_resource(Loopback(loopback.clone()))
+            .insert_resource(PortResource(8080));
+        update_until_listening(&mut server);
+
+        let _connection = futures::executor::block_on(loopback.connect(
+            "ws://localhost:8080".into_client_request().unwrap(),
+            WebsocketSettings::default(),
+            task_pool,
+        ))
+        .unwrap();
+        update_until(&mut server, |ws| ws.clients().count() == 1);
+        let mut ws = server.world.resource_mut::<WebsocketServerResource>();
+        let alice = ws.clients().map(|(client_id, _)| client_id).next().unwrap();
+        assert!(ws.join_group("red", alice));
+
+        // the server restarts while alice is still connected
+        ws.listen("localhost:8080");
+        update_until(&mut server, |ws| ws.is_accepting());
+
+        let ws = server.world.resource::<WebsocketServerResource>();
+        assert_eq!(ws.group_members("red").count(), 0);
+        assert_eq!(ws.groups_of(alice).count(), 0);
+    }
+
+    #[test]
+    fn remote_address_comes_from_trusted_proxy_header() {
+        let _res = env_logger::builder()
+            .filter_level(LevelFilter::Info)
+            .try_init();
+        let remote_addresses: Arc<Mutex<Vec<Option<IpAddr>>>> = Default::default();
+        let mut server = App::new();
+        server
+            .insert_resource(WebsocketServerSettings {
+                trusted_proxies: vec![IpAddr::from([127, 0, 0, 1])],
+                ..Default::default()
+            })
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .add_startup_system(startup_server_on_ipv4)
+            .add_system(record_remote_addresses)
+            .insert_resource(RemoteAddresses(remote_addresses.clone()))
+            .insert_resource(PortResource(0));
+        update_until_listening(&mut server);
+        let url = format!("ws://127.0.0.1:{}/", listening_port(&server));
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut through_proxy = url.as_str().into_client_request().unwrap();
+        // the first address was made up by the client, the second was added by our proxy
+        through_proxy.headers_mut().insert(
+            "x-forwarded-for",
+            "198.51.100.1, 203.0.113.7".parse().unwrap(),
+        );
+        let _proxied = runtime
+            .block_on(tokio_tungstenite::connect_async(through_proxy))
+            .unwrap();
+        update_until(&mut server, |_| remote_addresses.lock().unwrap().len() == 1);
+        let _direct = runtime
+            .block_on(tokio_tungstenite::connect_async(&url))
+            .unwrap();
+        update_until(&mut server, |_| remote_addresses.lock().unwrap().len() == 2);
+
+        assert_eq!(
+            *remote_addresses.lock().unwrap(),
+            vec![
+                Some(IpAddr::from([203, 0, 113, 7])),
+                Some(IpAddr::from([127, 0, 0, 1]))
+            ]
+        );
     }
 }
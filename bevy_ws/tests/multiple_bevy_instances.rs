@@ -16,8 +16,8 @@ mod tests {
     fn run_bevy(name: String, running: Arc<Mutex<bool>>) {
         App::new()
             .add_plugins(MinimalPlugins)
-            .add_startup_system(startup.system())
-            .add_system(shutdown.system())
+            .add_startup_system(startup)
+            .add_system(shutdown)
             .insert_resource(RunResource { running })
             .insert_resource(NameResource(name))
             .run();
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use bevy_ws::{Identity, Rejection, UpgradeRequest};
+
+use crate::network::{KEY_QUERY_PARAMETER, PLAYER_QUERY_PARAMETER};
+
+/// The players allowed on a server started with `--accounts`, who are rated by name. The file
+/// is TOML with a key for every name, `alice = "s3cret"`
+#[derive(Debug, Clone, Default)]
+pub struct Accounts {
+    keys: HashMap<String, String>,
+}
+
+impl Accounts {
+    pub fn load(path: &Path) -> Result<Self, AccountsError> {
+        let text = std::fs::read_to_string(path).map_err(AccountsError::Io)?;
+        let keys = toml::from_str(&text).map_err(AccountsError::Parse)?;
+        Ok(Self { keys })
+    }
+
+    /// Lets in whoever sends the name of an account and its key, see [`crate::room::server_address`]
+    pub fn authenticate(&self, request: &UpgradeRequest) -> Result<Identity, Rejection> {
+        let (player, key) = match (
+            request.query(PLAYER_QUERY_PARAMETER),
+            request.query(KEY_QUERY_PARAMETER),
+        ) {
+            (Some(player), Some(key)) => (player, key),
+            _ => return Err(Rejection::unauthorized("log in with --name and --key")),
+        };
+        match self.keys.get(&player) {
+            Some(known) if *known == key => Ok(Identity(player)),
+            _ => Err(Rejection::unauthorized("wrong name or key")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AccountsError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+}
+
+impl fmt::Display for AccountsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountsError::Io(e) => write!(f, "can't read accounts: {}", e),
+            AccountsError::Parse(e) => write!(f, "bad accounts: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AccountsError {}
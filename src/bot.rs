@@ -0,0 +1,73 @@
+use bevy::prelude::*;
+use bevy_ws::{WebsocketClientEvent, WebsocketResource};
+use log::info;
+
+use crate::cli::Args;
+use crate::network::{ClientMessage, MovePaddle, ServerMessage};
+use crate::send_name;
+
+// how far from the middle the bot moves its paddle
+const REACH: f32 = 300.0;
+// seconds to go from one side and back
+const PERIOD: f32 = 4.0;
+
+/// Plays without a window by moving its paddle back and forth, for filling up games
+/// and load testing
+pub struct BotPlugin;
+
+impl Plugin for BotPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<BotPlayer>();
+        app.add_system(join_as_bot);
+        app.add_system(move_bot_paddle);
+    }
+}
+
+#[derive(Default)]
+struct BotPlayer(Option<u64>);
+
+fn join_as_bot(
+    net: Res<WebsocketResource>,
+    args: Res<Args>,
+    mut bot: ResMut<BotPlayer>,
+    mut network_event_reader: EventReader<WebsocketClientEvent>,
+) {
+    for event in network_event_reader.iter() {
+        let msg = match event {
+            WebsocketClientEvent::OnMessage(msg) => msg,
+            WebsocketClientEvent::OnClose => {
+                bot.0 = None;
+                continue;
+            }
+            WebsocketClientEvent::OnOpen(_) => continue,
+        };
+        if let Ok(ServerMessage::Welcome(id)) = serde_json::from_str(msg) {
+            info!("Bot playing as player {}", id);
+            bot.0 = Some(id);
+            send_name(&net, args.player_name.as_deref().unwrap_or("Bot"));
+        }
+    }
+}
+
+fn move_bot_paddle(
+    time: Res<Time>,
+    net: Res<WebsocketResource>,
+    bot: Res<BotPlayer>,
+    mut ten_times_per_second: Local<Option<Timer>>,
+) {
+    let timer = ten_times_per_second.get_or_insert_with(|| Timer::from_seconds(0.1, true));
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let player_id = match bot.0 {
+        Some(player_id) => player_id,
+        None => return,
+    };
+    let phase = time.seconds_since_startup() as f32 / PERIOD * std::f32::consts::TAU;
+    let msg = ClientMessage::MovePaddle(MovePaddle {
+        player_id,
+        position: phase.sin() * REACH,
+    });
+    let str = serde_json::to_string(&msg).expect("unable to serialize json");
+    net.broadcast(str);
+}
@@ -0,0 +1,402 @@
+use std::env;
+use std::str::FromStr;
+
+use bevy::log::Level;
+#[cfg(feature = "native")]
+use bevy_ws::NetworkConditions;
+
+use crate::network::Rules;
+
+// the browser has nowhere to show it
+#[cfg(not(target_arch = "wasm32"))]
+const USAGE: &str = "\
+Usage: pong-royale [server|client|bot] [options]
+
+Commands:
+  server    host games
+  client    play, the default
+  bot       play without a window, moving the paddle back and forth
+
+Options, each can also be set with the environment variable after it:
+  --bind <address>          server: where to listen [PONG_BIND, default localhost:8080]
+  --config <file>           server: TOML file with the matches, arena and rules, the rules
+                            are reloaded for new matches when it changes [PONG_CONFIG]
+  --server-url <url>        client, bot: server to connect to
+                            [PONG_SERVER_URL, default ws://localhost:8080]
+  --tick-rate <hz>          server, bot: frames per second [PONG_TICK_RATE, default 60]
+  --log-level <level>       error, warn, info, debug or trace [PONG_LOG_LEVEL, default info]
+  --name <name>             client, bot: what other players see [PONG_PLAYER_NAME]
+  --key <key>               client, bot: log in as --name on a server with --accounts
+                            [PONG_KEY]
+  --room <code>             client, bot: join the private room <code>, `new` makes a room
+                            for you to host [PONG_ROOM]
+  --rules <rules>           client: rules of the room you host, like lives=5,paddle_speed=600,
+                            the others are ball_speed [PONG_RULES]
+  --http-port <port>        server: serve /metrics, /healthz and /readyz [PONG_HTTP_PORT]
+  --accounts <file>         server: TOML file with the key of every player name, only
+                            they can play and are rated [PONG_ACCOUNTS]
+  --static-files <dir>      server: serve the web client from <dir> on the game port
+                            [PONG_STATIC_FILES]
+  --tls-cert <file>         server: PEM certificate chain to serve wss:// [PONG_TLS_CERT]
+  --tls-key <file>          server: PKCS #8 private key for --tls-cert [PONG_TLS_KEY]
+  --network-conditions <profile>
+                            simulate a bad connection: perfect, lan, broadband, mobile or
+                            terrible [PONG_NETWORK_CONDITIONS]
+  -h, --help                show this
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Server,
+    Client,
+    Bot,
+}
+
+/// How the game was started, the command line wins over the environment
+#[derive(Debug, Clone)]
+pub struct Args {
+    pub mode: Mode,
+    pub bind: String,
+    pub config: Option<String>,
+    pub server_url: String,
+    pub tick_rate: f64,
+    pub log_level: Level,
+    pub player_name: Option<String>,
+    pub key: Option<String>,
+    pub room: Option<String>,
+    pub rules: Option<Rules>,
+    pub http_port: Option<u16>,
+    pub accounts: Option<String>,
+    pub static_files: Option<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    #[cfg(feature = "native")]
+    pub network_conditions: Option<NetworkConditions>,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Client,
+            bind: "localhost:8080".to_string(),
+            config: None,
+            server_url: "ws://localhost:8080".to_string(),
+            tick_rate: 60.0,
+            log_level: Level::INFO,
+            player_name: None,
+            key: None,
+            room: None,
+            rules: None,
+            http_port: None,
+            accounts: None,
+            static_files: None,
+            tls_cert: None,
+            tls_key: None,
+            #[cfg(feature = "native")]
+            network_conditions: None,
+        }
+    }
+}
+
+impl Args {
+    /// Parses the process arguments and environment, exits on `--help` or a bad argument
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_env() -> Self {
+        let var = |name: &str| env::var(name).ok();
+        match Self::parse(env::args().skip(1), var) {
+            Ok(Some(args)) => args,
+            Ok(None) => {
+                print!("{}", USAGE);
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprint!("{}\n\n{}", e, USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    /// In the browser the page is the environment, see [`crate::page::var`]. Exiting would
+    /// only stop the page, so a bad value is logged to the console and the defaults are used
+    #[cfg(target_arch = "wasm32")]
+    pub fn from_env() -> Self {
+        match Self::parse(env::args().skip(1), crate::page::var) {
+            Ok(Some(args)) => args,
+            Ok(None) => Self::default(),
+            Err(e) => {
+                let message = format!("{}, playing with the defaults", e);
+                web_sys::console::error_1(&message.into());
+                Self::default()
+            }
+        }
+    }
+
+    // `None` when help was asked for
+    fn parse(
+        args: impl Iterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<Self>, String> {
+        let mut parsed = Self::default();
+        let mut flags = vec![];
+        let mut args = args.peekable();
+        let mode = match args.peek().map(String::as_str) {
+            // `--server` is how the server used to be started
+            Some("server" | "--server") => Some(Mode::Server),
+            Some("client") => Some(Mode::Client),
+            Some("bot") => Some(Mode::Bot),
+            _ => None,
+        };
+        if let Some(mode) = mode {
+            parsed.mode = mode;
+            args.next();
+        }
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Ok(None);
+            }
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), value.to_string()),
+                None => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("{} needs a value", arg))?;
+                    (arg, value)
+                }
+            };
+            flags.push((flag, value));
+        }
+        // the environment first so the command line overrides it
+        let from_env = OPTIONS
+            .iter()
+            .filter_map(|(flag, variable)| Some((flag.to_string(), env(variable)?)));
+        for (flag, value) in from_env.collect::<Vec<_>>().into_iter().chain(flags) {
+            parsed.set(&flag, value)?;
+        }
+        if parsed.tls_cert.is_some() != parsed.tls_key.is_some() {
+            return Err("--tls-cert and --tls-key have to be used together".to_string());
+        }
+        if parsed.key.is_some() && parsed.player_name.is_none() {
+            return Err("--key needs a --name to log in as".to_string());
+        }
+        Ok(Some(parsed))
+    }
+
+    fn set(&mut self, flag: &str, value: String) -> Result<(), String> {
+        match flag {
+            "--bind" => self.bind = value,
+            "--config" => self.config = Some(value),
+            "--server-url" => self.server_url = value,
+            "--tick-rate" => {
+                self.tick_rate = parse(flag, &value)?;
+                if !self.tick_rate.is_finite() || self.tick_rate <= 0.0 {
+                    return Err(format!("{} has to be more than 0", flag));
+                }
+            }
+            "--log-level" => self.log_level = parse(flag, &value)?,
+            "--name" => self.player_name = Some(value),
+            "--key" => self.key = Some(value),
+            "--room" => self.room = Some(value),
+            "--rules" => self.rules = Some(parse_rules(flag, &value)?),
+            "--http-port" => self.http_port = Some(parse(flag, &value)?),
+            "--accounts" => self.accounts = Some(value),
+            "--static-files" => self.static_files = Some(value),
+            "--tls-cert" => self.tls_cert = Some(value),
+            "--tls-key" => self.tls_key = Some(value),
+            #[cfg(feature = "native")]
+            "--network-conditions" => {
+                let conditions = value.parse::<NetworkConditions>();
+                self.network_conditions = Some(conditions.map_err(|e| e.to_string())?);
+            }
+            _ => return Err(format!("Unknown option {}", flag)),
+        }
+        Ok(())
+    }
+}
+
+// every option and the environment variable that can set it instead
+const OPTIONS: &[(&str, &str)] = &[
+    ("--bind", "PONG_BIND"),
+    ("--config", "PONG_CONFIG"),
+    ("--server-url", "PONG_SERVER_URL"),
+    ("--tick-rate", "PONG_TICK_RATE"),
+    ("--log-level", "PONG_LOG_LEVEL"),
+    ("--name", "PONG_PLAYER_NAME"),
+    ("--key", "PONG_KEY"),
+    ("--room", "PONG_ROOM"),
+    ("--rules", "PONG_RULES"),
+    ("--http-port", "PONG_HTTP_PORT"),
+    ("--accounts", "PONG_ACCOUNTS"),
+    ("--static-files", "PONG_STATIC_FILES"),
+    ("--tls-cert", "PONG_TLS_CERT"),
+    ("--tls-key", "PONG_TLS_KEY"),
+    ("--network-conditions", "PONG_NETWORK_CONDITIONS"),
+];
+
+fn parse<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Bad value for {}: {}", flag, value))
+}
+
+// `lives=5,paddle_speed=600`, the rules left out keep their defaults
+fn parse_rules(flag: &str, value: &str) -> Result<Rules, String> {
+    let mut rules = Rules::default();
+    for rule in value.split(',').filter(|rule| !rule.trim().is_empty()) {
+        let (name, number) = rule
+            .split_once('=')
+            .ok_or_else(|| format!("Bad value for {}: {}", flag, rule))?;
+        let number = number.trim();
+        match name.trim() {
+            "lives" => rules.lives = parse(flag, number)?,
+            "ball_speed" => rules.ball_speed = parse(flag, number)?,
+            "paddle_speed" => rules.paddle_speed = parse(flag, number)?,
+            name => return Err(format!("Unknown rule {}", name)),
+        }
+    }
+    Ok(rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_with(
+        args: &[&str],
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<Args>, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()), env)
+    }
+
+    fn parse(args: &[&str]) -> Result<Option<Args>, String> {
+        parse_with(args, |_| None)
+    }
+
+    fn parsed(args: &[&str]) -> Args {
+        parse(args).unwrap().expect("no help asked for")
+    }
+
+    #[test]
+    fn command_line_overrides_the_environment() {
+        let env = |name: &str| match name {
+            "PONG_BIND" => Some("0.0.0.0:9000".to_string()),
+            "PONG_TICK_RATE" => Some("30".to_string()),
+            _ => None,
+        };
+        let args = parse_with(&["server", "--bind", "localhost:9001"], env)
+            .unwrap()
+            .unwrap();
+        assert_eq!(args.mode, Mode::Server);
+        assert_eq!(args.bind, "localhost:9001");
+        assert_eq!(args.tick_rate, 30.0);
+    }
+
+    #[test]
+    fn values_come_after_a_space_or_an_equals_sign() {
+        let spaced = parsed(&["bot", "--name", "alice", "--rules", "lives=5"]);
+        let joined = parsed(&["bot", "--name=alice", "--rules=lives=5"]);
+        for args in [spaced, joined] {
+            assert_eq!(args.mode, Mode::Bot);
+            assert_eq!(args.player_name.as_deref(), Some("alice"));
+            assert_eq!(args.rules.map(|rules| rules.lives), Some(5));
+        }
+        assert_eq!(parse(&["--name"]).unwrap_err(), "--name needs a value");
+    }
+
+    #[test]
+    fn mode_defaults_to_client() {
+        let args = parsed(&[]);
+        assert_eq!(args.mode, Mode::Client);
+        assert_eq!(args.server_url, "ws://localhost:8080");
+        assert_eq!(parsed(&["--server"]).mode, Mode::Server);
+    }
+
+    #[test]
+    fn tls_needs_both_certificate_and_key() {
+        let error = "--tls-cert and --tls-key have to be used together";
+        assert_eq!(
+            parse(&["server", "--tls-cert", "cert.pem"]).unwrap_err(),
+            error
+        );
+        assert_eq!(
+            parse(&["server", "--tls-key", "key.pem"]).unwrap_err(),
+            error
+        );
+        let env = |name: &str| (name == "PONG_TLS_KEY").then(|| "key.pem".to_string());
+        let args = parse_with(&["server", "--tls-cert", "cert.pem"], env)
+            .unwrap()
+            .unwrap();
+        assert_eq!(args.tls_cert.as_deref(), Some("cert.pem"));
+        assert_eq!(args.tls_key.as_deref(), Some("key.pem"));
+    }
+
+    #[test]
+    fn rules_are_parsed_by_name() {
+        let rules = parse_rules("--rules", "lives=5, paddle_speed=600,").unwrap();
+        assert_eq!(
+            rules,
+            Rules {
+                lives: 5,
+                paddle_speed: 600.0,
+                ..Rules::default()
+            }
+        );
+        assert_eq!(
+            parse_rules("--rules", "gravity=2").unwrap_err(),
+            "Unknown rule gravity"
+        );
+        assert_eq!(
+            parse_rules("--rules", "lives=many").unwrap_err(),
+            "Bad value for --rules: many"
+        );
+        assert_eq!(
+            parse_rules("--rules", "lives").unwrap_err(),
+            "Bad value for --rules: lives"
+        );
+    }
+
+    #[test]
+    fn help_wins_over_everything_else() {
+        assert!(parse(&["--help"]).unwrap().is_none());
+        assert!(parse(&["server", "--bind", "localhost:1", "-h"])
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn bad_values_are_refused() {
+        assert_eq!(
+            parse(&["--colour", "red"]).unwrap_err(),
+            "Unknown option --colour"
+        );
+        assert_eq!(
+            parse(&["--tick-rate", "0"]).unwrap_err(),
+            "--tick-rate has to be more than 0"
+        );
+        assert_eq!(
+            parse(&["--http-port", "http"]).unwrap_err(),
+            "Bad value for --http-port: http"
+        );
+        assert_eq!(
+            parse(&["--key", "s3cret"]).unwrap_err(),
+            "--key needs a --name to log in as"
+        );
+        let env = |name: &str| (name == "PONG_LOG_LEVEL").then(|| "loud".to_string());
+        assert_eq!(
+            parse_with(&[], env).unwrap_err(),
+            "Bad value for --log-level: loud"
+        );
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn network_conditions_are_checked_up_front() {
+        let args = parsed(&["--network-conditions", "mobile"]);
+        assert_eq!(args.network_conditions, Some(NetworkConditions::mobile()));
+        let error = parse(&["--network-conditions", "dialup"]).unwrap_err();
+        assert!(
+            error.starts_with("unknown network profile 'dialup'"),
+            "{}",
+            error
+        );
+    }
+}
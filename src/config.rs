@@ -0,0 +1,345 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use bevy::prelude::*;
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::network::Rules;
+
+// how often the config file is checked for changes
+const WATCH_INTERVAL: f32 = 2.0;
+
+/// The server's settings, from the `--config` TOML file or the defaults.
+/// The players, arena and ratings file only take effect on startup, the rules with the next match
+/// and the matches and matchmaking right away
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameConfig {
+    /// paddles in one match
+    pub max_players: usize,
+    /// matches played at the same time, everyone after that is a spectator, reloaded with the file
+    pub max_matches: usize,
+    pub arena: Arena,
+    pub rules: Rules,
+    pub matchmaking: Matchmaking,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            max_players: 16,
+            max_matches: 8,
+            arena: Arena::default(),
+            rules: Rules::default(),
+            matchmaking: Matchmaking::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Arena {
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        Self {
+            width: 800.0,
+            height: 600.0,
+        }
+    }
+}
+
+/// How the queue puts players of similar skill together, see [`crate::matchmaking`]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Matchmaking {
+    /// queued players it takes to start a match
+    pub min_players: usize,
+    /// how far apart ratings can be for players who just queued
+    pub window: f64,
+    /// how much wider the window gets every second someone waits
+    pub window_growth: f64,
+    /// where ratings are kept between restarts, they're forgotten without it
+    pub ratings_file: Option<PathBuf>,
+}
+
+impl Default for Matchmaking {
+    fn default() -> Self {
+        Self {
+            min_players: 2,
+            window: 100.0,
+            window_growth: 10.0,
+            ratings_file: None,
+        }
+    }
+}
+
+impl GameConfig {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let config = Self::read(path)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // the file as it is, see `validate`
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        toml::from_str(&text).map_err(ConfigError::Parse)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_players == 0 {
+            return Err(ConfigError::Invalid(
+                "max_players has to be at least 1".to_string(),
+            ));
+        }
+        if self.max_matches == 0 {
+            return Err(ConfigError::Invalid(
+                "max_matches has to be at least 1".to_string(),
+            ));
+        }
+        if self.matchmaking.min_players == 0 || self.matchmaking.min_players > self.max_players {
+            return Err(ConfigError::Invalid(
+                "matchmaking.min_players has to be between 1 and max_players".to_string(),
+            ));
+        }
+        for (name, value) in [
+            ("matchmaking.window", self.matchmaking.window),
+            ("matchmaking.window_growth", self.matchmaking.window_growth),
+        ] {
+            if !(value.is_finite() && value >= 0.0) {
+                return Err(ConfigError::Invalid(format!(
+                    "{} can't be less than 0, is {}",
+                    name, value
+                )));
+            }
+        }
+        positive("arena.width", self.arena.width).map_err(ConfigError::Invalid)?;
+        positive("arena.height", self.arena.height).map_err(ConfigError::Invalid)?;
+        validate_rules(&self.rules).map_err(|e| ConfigError::Invalid(format!("rules.{}", e)))
+    }
+}
+
+/// Why `rules` can't be played by, also used for the rules of private rooms
+pub fn validate_rules(rules: &Rules) -> Result<(), String> {
+    if rules.lives == 0 {
+        return Err("lives has to be at least 1".to_string());
+    }
+    positive("ball_speed", rules.ball_speed)?;
+    positive("paddle_speed", rules.paddle_speed)
+}
+
+fn positive(name: &str, value: f32) -> Result<(), String> {
+    if value.is_finite() && value > 0.0 {
+        Ok(())
+    } else {
+        Err(format!("{} has to be more than 0, is {}", name, value))
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "can't read config: {}", e),
+            ConfigError::Parse(e) => write!(f, "bad config: {}", e),
+            ConfigError::Invalid(reason) => write!(f, "bad config: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Reloads the config file when it changes, see [`GameConfig`]
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        Self {
+            modified: modified(&path),
+            path,
+        }
+    }
+
+    // the file again if it changed, structural fields stay as they are in `current`, `None` when
+    // nothing changed or the file is broken
+    fn reload(&mut self, current: &GameConfig) -> Option<GameConfig> {
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        let mut reloaded = match GameConfig::read(&self.path) {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                warn!("Keeping the current config, {}", e);
+                return None;
+            }
+        };
+        if reloaded.max_players != current.max_players
+            || reloaded.arena != current.arena
+            || reloaded.matchmaking.ratings_file != current.matchmaking.ratings_file
+        {
+            warn!("max_players, arena and ratings_file only change on restart");
+            reloaded.max_players = current.max_players;
+            reloaded.arena = current.arena;
+            reloaded.matchmaking.ratings_file = current.matchmaking.ratings_file.clone();
+        }
+        // checked with the structural fields it's going to run with
+        if let Err(e) = reloaded.validate() {
+            warn!("Keeping the current config, {}", e);
+            return None;
+        }
+        if reloaded == *current {
+            return None;
+        }
+        info!(
+            "Config changed, new matches play by {:?}, matchmaking {:?}",
+            reloaded.rules, reloaded.matchmaking
+        );
+        Some(reloaded)
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+pub fn watch_config_file(
+    time: Res<Time>,
+    mut watcher: ResMut<ConfigWatcher>,
+    mut config: ResMut<GameConfig>,
+    mut check: Local<Option<Timer>>,
+) {
+    let check = check.get_or_insert_with(|| Timer::from_seconds(WATCH_INTERVAL, true));
+    if !check.tick(time.delta()).just_finished() {
+        return;
+    }
+    if let Some(reloaded) = watcher.reload(&config) {
+        *config = reloaded;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<GameConfig, ConfigError> {
+        let config: GameConfig = toml::from_str(text).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn invalid(text: &str) -> String {
+        match parse(text) {
+            Err(ConfigError::Invalid(reason)) => reason,
+            other => panic!("expected {:?} to be invalid, got {:?}", text, other),
+        }
+    }
+
+    // a file of its own for every test, they run at the same time
+    fn config_file(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pong-royale-{}.toml", name));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn rewrite(watcher: &mut ConfigWatcher, text: &str) {
+        std::fs::write(&watcher.path, text).unwrap();
+        // the modification time may not have moved on so soon after the last write
+        watcher.modified = None;
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(parse("").unwrap(), GameConfig::default());
+        let example = include_str!("../pong-royale.toml");
+        assert!(parse(example).is_ok());
+    }
+
+    #[test]
+    fn bad_values_are_rejected() {
+        assert!(invalid("max_players = 0").contains("max_players"));
+        assert!(invalid("max_matches = 0").contains("max_matches"));
+        assert!(invalid("[matchmaking]\nmin_players = 0").contains("min_players"));
+        assert!(invalid("max_players = 2\n[matchmaking]\nmin_players = 3").contains("min_players"));
+        assert!(invalid("[matchmaking]\nwindow = -1.0").contains("matchmaking.window"));
+        assert!(invalid("[matchmaking]\nwindow_growth = nan").contains("window_growth"));
+        assert!(invalid("[arena]\nwidth = 0.0").contains("arena.width"));
+        assert!(invalid("[arena]\nheight = inf").contains("arena.height"));
+        assert!(invalid("[rules]\nlives = 0").contains("rules.lives"));
+        assert!(invalid("[rules]\nball_speed = -300.0").contains("rules.ball_speed"));
+        assert!(matches!(
+            parse("max_player = 4"),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn reload_keeps_structural_fields() {
+        let path = config_file("structural", "max_players = 4\nmax_matches = 2");
+        let mut watcher = ConfigWatcher::new(&path);
+        let current = GameConfig::load(&path).unwrap();
+        assert_eq!(watcher.reload(&current), None);
+
+        rewrite(
+            &mut watcher,
+            "max_players = 8\nmax_matches = 3\n[arena]\nwidth = 100.0\n[rules]\nlives = 5\n\
+             [matchmaking]\nwindow = 50.0\nratings_file = \"ratings.json\"",
+        );
+        let reloaded = watcher.reload(&current).unwrap();
+        assert_eq!(reloaded.max_players, 4);
+        assert_eq!(reloaded.arena, current.arena);
+        assert_eq!(reloaded.matchmaking.ratings_file, None);
+        assert_eq!(reloaded.max_matches, 3);
+        assert_eq!(reloaded.rules.lives, 5);
+        assert_eq!(reloaded.matchmaking.window, 50.0);
+
+        // only structural changes, nothing to apply
+        rewrite(&mut watcher, "max_players = 8\nmax_matches = 2");
+        assert_eq!(watcher.reload(&current), None);
+
+        // fine for 8 players, but the server keeps running with 4
+        rewrite(
+            &mut watcher,
+            "max_players = 8\nmax_matches = 3\n[matchmaking]\nmin_players = 6",
+        );
+        assert_eq!(watcher.reload(&current), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn broken_reload_keeps_the_config() {
+        let path = config_file("broken", "[rules]\nlives = 2");
+        let mut watcher = ConfigWatcher::new(&path);
+        let current = GameConfig::load(&path).unwrap();
+
+        rewrite(&mut watcher, "[rules]\nlives = 0");
+        assert_eq!(watcher.reload(&current), None);
+        rewrite(&mut watcher, "[rules\nlives = 3");
+        assert_eq!(watcher.reload(&current), None);
+        std::fs::remove_file(&path).unwrap();
+        watcher.modified = Some(SystemTime::UNIX_EPOCH);
+        assert_eq!(watcher.reload(&current), None);
+
+        // fixing the file afterwards still gets picked up
+        rewrite(&mut watcher, "[rules]\nlives = 3");
+        assert_eq!(watcher.reload(&current).unwrap().rules.lives, 3);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use bevy_ws::WebsocketServerResource;
+
+use crate::http::{Response, Routes};
+
+// a frame taking longer than this means the game is stuck
+const TICK_DEADLINE: Duration = Duration::from_secs(5);
+
+/// `GET /healthz` answers 200 while frames keep coming, `GET /readyz` while players can connect,
+/// see [`crate::http::HttpPlugin`]
+pub struct HealthPlugin;
+
+impl Plugin for HealthPlugin {
+    fn build(&self, app: &mut App) {
+        let health = Health::default();
+        let routes = app
+            .world
+            .get_resource_or_insert_with(Routes::default)
+            .clone();
+        let alive = health.clone();
+        routes.add("/healthz", move || match alive.since_last_tick() {
+            since if since <= TICK_DEADLINE => Response::ok("ok"),
+            since => Response::unavailable(format!("no tick for {:?}", since)),
+        });
+        let ready = health.clone();
+        routes.add("/readyz", move || {
+            if ready.accepting.load(Ordering::SeqCst) {
+                Response::ok("ok")
+            } else {
+                Response::unavailable("not accepting players")
+            }
+        });
+        app.insert_resource(health);
+        app.add_system(report_tick);
+        app.add_system(report_accepting);
+    }
+}
+
+// written by the game loop, read by the http thread
+#[derive(Clone)]
+struct Health {
+    started_at: Instant,
+    // since `started_at`
+    last_tick_ms: Arc<AtomicU64>,
+    accepting: Arc<AtomicBool>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            started_at: Instant::now(),
+            last_tick_ms: Default::default(),
+            accepting: Default::default(),
+        }
+    }
+}
+
+impl Health {
+    fn since_last_tick(&self) -> Duration {
+        let last_tick = Duration::from_millis(self.last_tick_ms.load(Ordering::SeqCst));
+        self.started_at.elapsed().saturating_sub(last_tick)
+    }
+}
+
+fn report_tick(health: Res<Health>) {
+    let now = health.started_at.elapsed().as_millis() as u64;
+    health.last_tick_ms.store(now, Ordering::SeqCst);
+}
+
+fn report_accepting(health: Res<Health>, server: Res<WebsocketServerResource>) {
+    health
+        .accepting
+        .store(server.is_accepting(), Ordering::SeqCst);
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use log::{info, warn};
+
+// how long a connection gets to send its request and read the response
+const DEADLINE: Duration = Duration::from_secs(5);
+// requests at once, probes and scrapers need a handful
+const MAX_CONNECTIONS: usize = 16;
+// the request line and headers together, there's never a body
+const MAX_REQUEST_BYTES: u64 = 8192;
+const MAX_HEADERS: usize = 64;
+
+/// Plain HTTP next to the game for whatever runs and watches the container,
+/// other plugins add their paths to [`Routes`]
+pub struct HttpPlugin {
+    pub port: u16,
+}
+
+impl Plugin for HttpPlugin {
+    fn build(&self, app: &mut App) {
+        let routes = app
+            .world
+            .get_resource_or_insert_with(Routes::default)
+            .clone();
+        // on every interface so it can be reached from outside the container
+        match TcpListener::bind(("0.0.0.0", self.port)) {
+            Ok(listener) => {
+                info!("Serving http on port {}", self.port);
+                thread::spawn(move || serve(listener, routes));
+            }
+            Err(e) => warn!("Failed to serve http on port {}: {:?}", self.port, e),
+        }
+    }
+}
+
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn ok(body: impl Into<String>) -> Self {
+        Self {
+            status: "200 OK",
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+    pub fn unavailable(body: impl Into<String>) -> Self {
+        Self {
+            status: "503 Service Unavailable",
+            ..Self::ok(body)
+        }
+    }
+    fn not_found() -> Self {
+        Self {
+            status: "404 Not Found",
+            ..Self::ok("")
+        }
+    }
+}
+
+type Handler = Box<dyn Fn() -> Response + Send + Sync>;
+
+/// What answers `GET` on each path, handlers run on the http thread
+#[derive(Default, Clone)]
+pub struct Routes(Arc<RwLock<HashMap<&'static str, Handler>>>);
+
+impl Routes {
+    pub fn add(&self, path: &'static str, handler: impl Fn() -> Response + Send + Sync + 'static) {
+        self.0
+            .write()
+            .expect("lock")
+            .insert(path, Box::new(handler));
+    }
+    fn respond(&self, path: &str) -> Response {
+        match self.0.read().expect("lock").get(path) {
+            Some(handler) => handler(),
+            None => Response::not_found(),
+        }
+    }
+}
+
+// every connection on its own thread, so a slow client can't hold up the probes
+fn serve(listener: TcpListener, routes: Routes) {
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Http connection failed:{:?}", e);
+                continue;
+            }
+        };
+        if open.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            open.fetch_sub(1, Ordering::SeqCst);
+            warn!("Dropped http connection, {} already open", MAX_CONNECTIONS);
+            continue;
+        }
+        let routes = routes.clone();
+        let open = open.clone();
+        thread::spawn(move || {
+            respond(stream, &routes).unwrap_or_else(|e| warn!("Http request failed:{:?}", e));
+            open.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+fn respond(mut stream: TcpStream, routes: &Routes) -> io::Result<()> {
+    let deadline = Instant::now() + DEADLINE;
+    stream.set_write_timeout(Some(DEADLINE))?;
+    let mut reader = BufReader::new(
+        UntilDeadline {
+            stream: &stream,
+            deadline,
+        }
+        .take(MAX_REQUEST_BYTES),
+    );
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // read the headers too, closing with unread data resets the connection
+    let mut headers = 0;
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if reader.read_line(&mut header)? == 0 || headers > MAX_HEADERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large or cut short",
+            ));
+        }
+        if header.trim_end().is_empty() {
+            break;
+        }
+        headers += 1;
+    }
+    let response = match request_line.split_whitespace().nth(1) {
+        Some(target) => {
+            let path = target.split('?').next().unwrap_or(target);
+            routes.respond(path)
+        }
+        None => Response::not_found(),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )
+}
+
+// reads that time out at `deadline` however little the client sends at a time
+struct UntilDeadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for UntilDeadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request too slow"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
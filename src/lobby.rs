@@ -0,0 +1,304 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use bevy_ws::WebsocketServerResource;
+use rand::Rng;
+
+use crate::network::Rules;
+
+// no 0, O, 1 or I, so codes can be read out loud and typed from a screenshot
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 5;
+
+/// Puts everyone on the server into matches of at most `max_players`, each with its own
+/// paddles, player ids and a bevy_ws group to broadcast to. Public matches are filled by the
+/// matchmaking queue and remember the order their players left in for the ratings
+#[derive(Default)]
+pub struct Lobby {
+    // by id, so the oldest match with room fills up first
+    matches: BTreeMap<u64, Match>,
+    // the match of every client in one
+    clients: HashMap<u64, u64>,
+    next_match_id: u64,
+}
+
+/// What [`Lobby::leave`] did when a match ended
+pub struct Ended {
+    /// the rated players, the last one to leave first
+    pub standings: Vec<String>,
+    /// spectators that moved on and the match they now watch
+    pub moved: Vec<(u64, u64)>,
+}
+
+/// One game, played by the rules it started with
+pub struct Match {
+    pub rules: Rules,
+    // only private rooms have a code, the queue never puts anyone in them
+    code: Option<String>,
+    // the client that configures and starts a private room, the first player left in it
+    host: Option<u64>,
+    started: bool,
+    players: Vec<u64>,
+    spectators: Vec<u64>,
+    next_player_id: u64,
+    // the ratings of the players the queue put in here, private rooms have none
+    ratings: HashMap<u64, f64>,
+    // what the rated players are known by
+    keys: HashMap<u64, String>,
+    // the rated players who left, in that order
+    standings: Vec<String>,
+}
+
+/// Why a private room can't be joined
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomError {
+    NotFound(String),
+    Full(String),
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomError::NotFound(code) => write!(f, "There's no room {}", code),
+            RoomError::Full(code) => write!(f, "Room {} is full", code),
+        }
+    }
+}
+
+impl Lobby {
+    /// Starts a public match by `rules` for the matchmaking queue to fill, see [`Self::join`]
+    pub fn open_public(&mut self, rules: &Rules) -> u64 {
+        self.open_match(rules, None)
+    }
+
+    /// Puts `client_id` in the public match `match_id`, rated by `key` when there is one.
+    /// Returns the player's id in it
+    pub fn join(
+        &mut self,
+        net: &mut WebsocketServerResource,
+        match_id: u64,
+        client_id: u64,
+        rating: f64,
+        key: Option<String>,
+    ) -> u64 {
+        let game = self.matches.get_mut(&match_id).expect("match just found");
+        game.ratings.insert(client_id, rating);
+        if let Some(key) = key {
+            game.keys.insert(client_id, key);
+        }
+        self.add_player(net, match_id, client_id)
+    }
+
+    /// Public matches with room for another player and the average rating of their players
+    pub fn public_matches(&self, max_players: usize) -> Vec<(u64, f64)> {
+        self.matches
+            .iter()
+            .filter(|(_, game)| game.code.is_none() && game.players.len() < max_players)
+            .map(|(match_id, game)| (*match_id, game.skill()))
+            .collect()
+    }
+
+    /// Makes a private room hosted by `client_id` with a new code, see [`Self::join_room`].
+    /// Returns the match and the player's id in it
+    pub fn create_room(
+        &mut self,
+        net: &mut WebsocketServerResource,
+        client_id: u64,
+        rules: &Rules,
+    ) -> (u64, u64) {
+        let code = loop {
+            let code = generate_code();
+            if self.find_room(&code).is_none() {
+                break code;
+            }
+        };
+        let match_id = self.open_match(rules, Some(code));
+        if let Some(game) = self.matches.get_mut(&match_id) {
+            game.host = Some(client_id);
+        }
+        (match_id, self.add_player(net, match_id, client_id))
+    }
+
+    /// Puts `client_id` in the private room with `code`, any case. Returns the match and the
+    /// player's id in it
+    pub fn join_room(
+        &mut self,
+        net: &mut WebsocketServerResource,
+        client_id: u64,
+        code: &str,
+        max_players: usize,
+    ) -> Result<(u64, u64), RoomError> {
+        let code = code.to_uppercase();
+        let match_id = self
+            .find_room(&code)
+            .ok_or_else(|| RoomError::NotFound(code.clone()))?;
+        if self.matches[&match_id].players.len() >= max_players {
+            return Err(RoomError::Full(code));
+        }
+        Ok((match_id, self.add_player(net, match_id, client_id)))
+    }
+
+    /// Lets `client_id` watch the private room with `code` or else the oldest public match,
+    /// `None` while there's nothing to watch
+    pub fn watch(
+        &mut self,
+        net: &mut WebsocketServerResource,
+        client_id: u64,
+        code: Option<&str>,
+    ) -> Option<u64> {
+        let match_id = match code {
+            Some(code) => self.find_room(&code.to_uppercase())?,
+            None => self
+                .matches
+                .iter()
+                .find(|(_, game)| game.code.is_none())
+                .map(|(match_id, _)| *match_id)?,
+        };
+        let game = self.matches.get_mut(&match_id)?;
+        game.spectators.push(client_id);
+        self.clients.insert(client_id, match_id);
+        net.join_group(&group(match_id), client_id);
+        Some(match_id)
+    }
+
+    /// Takes `client_id` out of its match. A match ends with its last player, its
+    /// spectators move on to another one. Closed clients leave the group by themselves.
+    /// Returns what became of the match if it just ended
+    pub fn leave(&mut self, net: &mut WebsocketServerResource, client_id: u64) -> Option<Ended> {
+        let match_id = self.clients.remove(&client_id)?;
+        let game = self.matches.get_mut(&match_id)?;
+        game.players.retain(|id| *id != client_id);
+        game.spectators.retain(|id| *id != client_id);
+        game.ratings.remove(&client_id);
+        if let Some(key) = game.keys.remove(&client_id) {
+            game.standings.push(key);
+        }
+        if game.host == Some(client_id) {
+            game.host = game.players.first().copied();
+        }
+        if !game.players.is_empty() {
+            return None;
+        }
+        let ended = self.matches.remove(&match_id)?;
+        let mut moved = vec![];
+        for spectator in ended.spectators {
+            self.clients.remove(&spectator);
+            net.leave_group(&group(match_id), spectator);
+            if let Some(match_id) = self.watch(net, spectator, None) {
+                moved.push((spectator, match_id));
+            }
+        }
+        let mut standings = ended.standings;
+        standings.reverse();
+        Some(Ended { standings, moved })
+    }
+
+    pub fn match_of(&self, client_id: u64) -> Option<u64> {
+        self.clients.get(&client_id).copied()
+    }
+
+    pub fn get(&self, match_id: u64) -> Option<&Match> {
+        self.matches.get(&match_id)
+    }
+
+    pub fn get_mut(&mut self, match_id: u64) -> Option<&mut Match> {
+        self.matches.get_mut(&match_id)
+    }
+
+    pub fn match_count(&self) -> usize {
+        self.matches.len()
+    }
+
+    /// Sends `message` to the connected players and spectators of `match_id`, suspended
+    /// ones catch up with a snapshot when they resume
+    pub fn broadcast(&self, net: &WebsocketServerResource, match_id: u64, message: &str) {
+        net.broadcast_to_group(&group(match_id), message.to_string());
+    }
+
+    fn open_match(&mut self, rules: &Rules, code: Option<String>) -> u64 {
+        let match_id = self.next_match_id;
+        self.next_match_id += 1;
+        self.matches.insert(
+            match_id,
+            Match {
+                rules: rules.clone(),
+                // private rooms wait for their host
+                started: code.is_none(),
+                code,
+                host: None,
+                players: vec![],
+                spectators: vec![],
+                next_player_id: 0,
+                ratings: HashMap::new(),
+                keys: HashMap::new(),
+                standings: vec![],
+            },
+        );
+        match_id
+    }
+
+    fn add_player(
+        &mut self,
+        net: &mut WebsocketServerResource,
+        match_id: u64,
+        client_id: u64,
+    ) -> u64 {
+        let game = self.matches.get_mut(&match_id).expect("match just found");
+        let player_id = game.next_player_id;
+        game.next_player_id += 1;
+        game.players.push(client_id);
+        self.clients.insert(client_id, match_id);
+        net.join_group(&group(match_id), client_id);
+        player_id
+    }
+
+    fn find_room(&self, code: &str) -> Option<u64> {
+        self.matches
+            .iter()
+            .find(|(_, game)| game.code.as_deref() == Some(code))
+            .map(|(match_id, _)| *match_id)
+    }
+}
+
+fn group(match_id: u64) -> String {
+    format!("match-{}", match_id)
+}
+
+fn generate_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CODE_LENGTH)
+        .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+impl Match {
+    pub fn players(&self) -> impl Iterator<Item = u64> + '_ {
+        self.players.iter().copied()
+    }
+
+    /// The join code of a private room
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+
+    pub fn host(&self) -> Option<u64> {
+        self.host
+    }
+
+    /// Public matches start right away, private rooms when their host says so
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    pub fn start(&mut self) {
+        self.started = true;
+    }
+
+    // the average rating of the players in it
+    fn skill(&self) -> f64 {
+        if self.ratings.is_empty() {
+            return 0.0;
+        }
+        self.ratings.values().sum::<f64>() / self.ratings.len() as f64
+    }
+}
@@ -1,36 +1,116 @@
-use std::env;
+#[cfg(feature = "graphics")]
+use std::collections::HashMap;
+#[cfg(feature = "graphics")]
 use std::ops::Not;
+#[cfg(feature = "native")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "native")]
+use std::sync::Arc;
 use std::time::Duration;
 
+#[cfg(feature = "native")]
+use bevy::app::AppExit;
+
+use bevy::log::LogPlugin;
 use bevy::prelude::*;
 use bevy::{
     app::{App, ScheduleRunnerSettings},
     core::Time,
     MinimalPlugins,
 };
+#[cfg(feature = "native")]
+use log::debug;
 use log::info;
 use log::warn;
-#[cfg(not(feature = "web"))]
-use log::LevelFilter;
 
 use bevy_ws::WebsocketClientEvent;
 use bevy_ws::WebsocketPlugin;
 use bevy_ws::WebsocketResource;
-#[cfg(not(feature = "web"))]
+#[cfg(feature = "native")]
 use bevy_ws::WebsocketServerEvent;
-#[cfg(not(feature = "web"))]
-use bevy_ws::{WebsocketServerPlugin, WebsocketServerResource};
+#[cfg(feature = "native")]
+use bevy_ws::{ConditionedTransport, NetworkConditions, TcpTransport};
+#[cfg(feature = "native")]
+use bevy_ws::{
+    Overflow, RateLimit, ServerDiagnostics, StaticFiles, WebsocketServerPlugin,
+    WebsocketServerResource, WebsocketServerSettings,
+};
 
+#[cfg(feature = "native")]
+use crate::accounts::Accounts;
+use crate::bot::BotPlugin;
+use crate::cli::{Args, Mode};
+#[cfg(feature = "native")]
+use crate::config::{validate_rules, watch_config_file, ConfigWatcher, GameConfig};
+#[cfg(feature = "native")]
+use crate::health::HealthPlugin;
+#[cfg(feature = "native")]
+use crate::http::HttpPlugin;
+#[cfg(feature = "native")]
+use crate::lobby::{Lobby, Match};
+#[cfg(feature = "native")]
+use crate::matchmaking::{MatchEnded, MatchmakingPlugin, Ratings};
+#[cfg(feature = "native")]
+use crate::metrics::{Metrics, MetricsPlugin};
+#[cfg(feature = "graphics")]
+use crate::network::Rules;
 use crate::network::{ClientMessage, MovePaddle, ServerMessage};
+#[cfg(feature = "native")]
+use crate::network::{Room, NEW_ROOM, ROOM_QUERY_PARAMETER};
+#[cfg(feature = "graphics")]
+use crate::room::start_match_on_enter;
+use crate::room::{server_address, CurrentRoom, RoomPlugin};
 
+#[cfg(feature = "native")]
+mod accounts;
+mod bot;
+mod cli;
+#[cfg(feature = "native")]
+mod config;
+#[cfg(feature = "native")]
+mod health;
+#[cfg(feature = "native")]
+mod http;
+#[cfg(feature = "native")]
+mod lobby;
+#[cfg(feature = "native")]
+mod matchmaking;
+#[cfg(feature = "native")]
+mod metrics;
 mod network;
+#[cfg(target_arch = "wasm32")]
+mod page;
+mod room;
 
-const PORT: u16 = 8080;
+// how long a disconnected player keeps its paddle
+#[cfg(feature = "native")]
+const SESSION_GRACE_PERIOD: Duration = Duration::from_secs(30);
+// clients send paddle moves ten times a second, this leaves room for hiccups but not floods
+#[cfg(feature = "native")]
+const CLIENT_RATE_LIMIT: RateLimit = RateLimit {
+    per_second: 20.0,
+    burst: 20,
+};
+// no game message comes close to this
+#[cfg(feature = "native")]
+const MAX_MESSAGE_SIZE: usize = 4 << 10;
+// how many dropped messages a client gets away with before it's disconnected
+#[cfg(feature = "native")]
+const MAX_VIOLATIONS: u32 = 100;
+// how often the server logs its traffic
+#[cfg(feature = "native")]
+const DIAGNOSTICS_INTERVAL: Duration = Duration::from_secs(60);
+// where the game socket is when the server also serves the web client
+const WEBSOCKET_PATH: &str = "/ws";
+// how long a client waits before trying to get back in after losing the connection
+const RECONNECT_DELAY: f32 = 1.0;
 
+#[cfg(feature = "graphics")]
 struct TenTimesPerSecond {
     timer: Timer,
 }
 
+#[cfg(feature = "graphics")]
 impl Default for TenTimesPerSecond {
     fn default() -> Self {
         Self {
@@ -46,6 +126,7 @@ fn main() {
     //     .filter_level(LevelFilter::Info)
     //     // .filter_module("pong-royale", LevelFilter::Info)
     //     .init();
+    let args = Args::from_env();
     #[cfg(not(target_arch = "wasm32"))]
     // let _res = env_logger::builder()
     //     .filter_level(LevelFilter::Info)
@@ -56,43 +137,111 @@ fn main() {
     app
         // minimal plugins necessary for timers + headless loop
         .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
-            1.0 / 60.0,
+            1.0 / args.tick_rate,
         )))
         .insert_resource(bevy::log::LogSettings {
-            level: bevy::log::Level::INFO,
+            level: args.log_level,
             // filter: "pong-royale::*=info".to_string(),
             ..Default::default()
         })
-        .insert_resource(TenTimesPerSecond::default());
-
-    if is_server() {
-        app.add_plugins(MinimalPlugins);
-        // app.add_plugin(LogPlugin::default());
-        #[cfg(not(feature = "web"))]
-        app.add_plugin(WebsocketServerPlugin);
-        #[cfg(not(feature = "web"))]
-        app.add_startup_system(startup_server.system());
-        #[cfg(not(feature = "web"))]
-        app.add_system(handle_packets_server.system());
-        #[cfg(not(feature = "web"))]
-        app.add_system(spawn_paddle_system_server.system());
-    } else {
-        #[cfg(not(feature = "headless"))]
-        {
-            app.add_plugins(DefaultPlugins);
+        .insert_resource(args.clone());
+
+    match args.mode {
+        Mode::Server => {
+            app.add_plugins(MinimalPlugins);
+            app.add_plugin(LogPlugin);
+            #[cfg(feature = "native")]
+            {
+                let config = load_config(&args);
+                app.insert_resource(load_ratings(&config));
+                app.insert_resource(config);
+            }
+            #[cfg(feature = "native")]
+            if let Some(path) = &args.accounts {
+                app.insert_resource(load_accounts(path));
+                app.add_startup_system(require_accounts);
+            }
+            #[cfg(feature = "native")]
+            if let Some(path) = &args.config {
+                app.insert_resource(ConfigWatcher::new(path));
+                app.add_system(watch_config_file);
+            }
+            #[cfg(feature = "native")]
+            app.init_resource::<Lobby>();
+            #[cfg(feature = "native")]
+            app.insert_resource(WebsocketServerSettings {
+                session_grace_period: SESSION_GRACE_PERIOD,
+                rate_limit: Some(CLIENT_RATE_LIMIT),
+                max_message_size: Some(MAX_MESSAGE_SIZE),
+                max_violations: Some(MAX_VIOLATIONS),
+                diagnostics_interval: Some(DIAGNOSTICS_INTERVAL),
+                static_files: args.static_files.clone().map(|root| StaticFiles {
+                    root: root.into(),
+                    websocket_path: WEBSOCKET_PATH.to_string(),
+                }),
+                ..Default::default()
+            });
+            #[cfg(feature = "native")]
+            app.add_plugin(WebsocketServerPlugin);
+            #[cfg(feature = "native")]
+            app.add_startup_system(startup_server);
+            #[cfg(feature = "native")]
+            app.add_system(handle_packets_server);
+            #[cfg(feature = "native")]
+            app.add_system(cap_clients);
+            #[cfg(feature = "native")]
+            app.add_plugin(MatchmakingPlugin);
+            #[cfg(feature = "native")]
+            app.add_system(spawn_paddle_system_server);
+            #[cfg(feature = "native")]
+            app.add_system(log_server_traffic);
+            #[cfg(feature = "native")]
+            if let Some(port) = args.http_port {
+                app.add_plugin(MetricsPlugin);
+                app.add_plugin(HealthPlugin);
+                app.add_plugin(HttpPlugin { port });
+                app.add_system(count_matches);
+            }
+            #[cfg(feature = "native")]
+            app.insert_resource(ShutdownRequested::install());
+            #[cfg(feature = "native")]
+            app.add_system(shutdown_on_signal);
+        }
+        Mode::Client => {
+            #[cfg(feature = "graphics")]
+            {
+                app.add_plugins(DefaultPlugins);
+                app.add_plugin(WebsocketPlugin);
+                app.add_plugin(RoomPlugin);
+                app.add_startup_system(client_startup);
+                app.add_startup_system(startup_client);
+                app.add_system(create_network_event_from_keyboard_input);
+                app.add_system(handle_packets_client);
+                app.add_system(reconnect_client);
+                app.add_system(spawn_paddle_system_client);
+                app.add_system(apply_rules_client);
+                app.add_system(start_match_on_enter);
+                app.insert_resource(PlayerId::default());
+                app.insert_resource(TenTimesPerSecond::default());
+                app.init_resource::<Rules>();
+            }
+            #[cfg(not(feature = "graphics"))]
+            {
+                eprintln!("The headless build has no window to play in, run server or bot");
+                std::process::exit(2);
+            }
+        }
+        Mode::Bot => {
+            app.add_plugins(MinimalPlugins);
+            app.add_plugin(LogPlugin);
             app.add_plugin(WebsocketPlugin);
-            app.add_startup_system(client_startup.system());
-            app.add_startup_system(startup_client.system());
-            app.add_system(create_network_event_from_keyboard_input.system());
-            app.add_system(handle_packets_client.system());
-            app.add_system(spawn_paddle_system_client.system());
-            app.insert_resource(PlayerId::default());
+            app.add_plugin(BotPlugin);
+            app.add_plugin(RoomPlugin);
+            app.add_startup_system(startup_client);
+            app.add_system(reconnect_client);
         }
     }
 
-    #[cfg(target_arch = "wasm32")]
-    app.add_plugin(bevy_webgl2::WebGL2Plugin);
-
     app
         // The NetworkingPlugin
         // .add_plugin(NetworkingPlugin {
@@ -101,121 +250,243 @@ fn main() {
         .add_event::<PlayerEcsEvent>();
     // Our networking
     // .insert_resource(parse_simple_args())
-    // .add_startup_system(startup.system());
+    // .add_startup_system(startup);
 
     app.run();
 }
 
+#[cfg(feature = "graphics")]
 #[derive(Component)]
 struct Paddle {
     speed: f32,
 }
 
+#[cfg(feature = "graphics")]
 #[derive(Component)]
 struct ControlledByPlayer {
     player_id: u64,
 }
 
+#[allow(dead_code)]
 enum PlayerEcsEvent {
     Connected(u64),
     Disconnected(u64),
 }
 
+#[cfg(feature = "graphics")]
 #[derive(Default)]
 struct PlayerId(Option<u64>);
 
-fn startup_client(mut ws: ResMut<WebsocketResource>) {
-    ws.open(format!("ws://localhost:{}", PORT).as_str());
+// what other players know a client as, the websocket client id stays between client and server
+#[cfg(feature = "native")]
+struct PublicPlayerId(u64);
+
+// what a player asked to be called
+#[cfg(feature = "native")]
+struct PlayerName(String);
+
+// last position a player reported for its paddle
+#[cfg(feature = "native")]
+struct PaddlePosition(f32);
+
+// set from the signal handler when the container is asked to stop
+#[cfg(feature = "native")]
+struct ShutdownRequested(Arc<AtomicBool>);
+
+#[cfg(feature = "native")]
+impl ShutdownRequested {
+    fn install() -> Self {
+        let requested = Arc::new(AtomicBool::new(false));
+        let handler_requested = requested.clone();
+        ctrlc::set_handler(move || {
+            if handler_requested.swap(true, Ordering::SeqCst) {
+                warn!("Got a second stop signal, exiting immediately");
+                std::process::exit(1);
+            }
+            info!("Got stop signal, shutting down");
+        })
+        .expect("unable to install signal handler");
+        Self(requested)
+    }
 }
 
-#[cfg(not(feature = "web"))]
-fn startup_server(mut ws: ResMut<WebsocketServerResource>) {
-    ws.listen(format!("localhost:{}", PORT).as_str());
+fn startup_client(mut ws: ResMut<WebsocketResource>, args: Res<Args>, room: Res<CurrentRoom>) {
+    #[cfg(feature = "native")]
+    if let Some(conditions) = network_conditions(&args) {
+        ws.set_transport(ConditionedTransport::new(
+            TcpTransport::default(),
+            conditions,
+        ));
+    }
+    ws.open(&server_address(&args, &room));
 }
 
-#[cfg(not(feature = "headless"))]
-fn client_startup(
-    mut commands: Commands,
-    mut materials: ResMut<Assets<ColorMaterial>>,
-    asset_server: Res<AssetServer>,
+// keeps trying to resume the session after the connection drops
+fn reconnect_client(
+    time: Res<Time>,
+    args: Res<Args>,
+    room: Res<CurrentRoom>,
+    mut ws: ResMut<WebsocketResource>,
+    mut network_event_reader: EventReader<WebsocketClientEvent>,
+    mut reconnect: Local<Option<Timer>>,
 ) {
-    if !is_server() {
-        commands
-            .spawn_bundle(SpriteBundle {
-                transform: Transform::from_xyz(0.0, 1. as f32 * 40. - 200., 0.0),
-                sprite: Sprite {
-                    color: Color::rgb(0.5, 0.5, 1.0),
-                    custom_size: Some(Vec2::new(120.0, 30.0)),
-                    ..default()
-                },
-                ..Default::default()
-            })
-            .insert(Paddle { speed: 500.0 })
-            .insert(ControlledByPlayer { player_id: 0 });
-
-        commands
-            .spawn_bundle(SpriteBundle {
-                transform: Transform::from_xyz(0.0, 2. as f32 * 40. - 200., 0.0),
-                sprite: Sprite {
-                    color: Color::rgb(0.5, 0.5, 1.0),
-                    custom_size: Some(Vec2::new(120.0, 30.0)),
-                    ..default()
-                },
-                ..Default::default()
+    for event in network_event_reader.iter() {
+        if let WebsocketClientEvent::OnClose = event {
+            reconnect.replace(Timer::from_seconds(RECONNECT_DELAY, false));
+        }
+    }
+    let finished = match reconnect.as_mut() {
+        Some(timer) => timer.tick(time.delta()).just_finished(),
+        None => false,
+    };
+    if finished {
+        reconnect.take();
+        info!("Trying to resume session");
+        ws.resume(&server_address(&args, &room));
+    }
+}
+
+// the defaults without `--config`, exits when the file is unusable like a bad argument would
+#[cfg(feature = "native")]
+fn load_config(args: &Args) -> GameConfig {
+    let path = match &args.config {
+        Some(path) => path,
+        None => return GameConfig::default(),
+    };
+    match GameConfig::load(path.as_ref()) {
+        Ok(config) => {
+            info!("Using config {}: {:?}", path, config);
+            config
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(2);
+        }
+    }
+}
+
+// forgotten on restart without a ratings file, exits when the file is unusable like a bad config
+#[cfg(feature = "native")]
+fn load_ratings(config: &GameConfig) -> Ratings {
+    let path = match &config.matchmaking.ratings_file {
+        Some(path) => path,
+        None => return Ratings::default(),
+    };
+    match Ratings::load(path) {
+        Ok(ratings) => {
+            info!("Using ratings {}", path.display());
+            ratings
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path.display(), e);
+            std::process::exit(2);
+        }
+    }
+}
+
+// exits when the file is unusable like a bad config
+#[cfg(feature = "native")]
+fn load_accounts(path: &str) -> Accounts {
+    match Accounts::load(path.as_ref()) {
+        Ok(accounts) => {
+            info!("Using accounts {}", path);
+            accounts
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(2);
+        }
+    }
+}
+
+// only players with an account get in, so their ratings are their own
+#[cfg(feature = "native")]
+fn require_accounts(accounts: Res<Accounts>, mut ws: ResMut<WebsocketServerResource>) {
+    let accounts = accounts.clone();
+    ws.set_authenticator(move |request| accounts.authenticate(request));
+}
+
+#[cfg(feature = "native")]
+fn startup_server(mut ws: ResMut<WebsocketServerResource>, args: Res<Args>) {
+    // the arguments make sure there are both or neither
+    let transport = match (&args.tls_cert, &args.tls_key) {
+        (Some(certificate), Some(private_key)) => {
+            info!("Serving wss:// with certificate {}", certificate);
+            TcpTransport::with_tls(certificate, private_key).unwrap_or_else(|e| {
+                eprintln!("Failed to set up TLS: {}", e);
+                std::process::exit(2);
             })
-            .insert(Paddle { speed: 500.0 })
-            .insert(ControlledByPlayer { player_id: 1 });
-
-        commands.spawn_bundle(OrthographicCameraBundle::new_2d());
-        commands.spawn_bundle(UiCameraBundle::default());
-
-        commands.spawn_bundle(TextBundle {
-            text: Text {
-                sections: vec![
-                    TextSection {
-                        value: "Player: ".to_string(),
-                        style: TextStyle {
-                            font: asset_server.load("fonts/FiraSans-Bold.ttf"),
-                            font_size: 40.0,
-                            color: Color::rgb(0.5, 0.5, 1.0),
-                        },
+        }
+        _ => TcpTransport::default(),
+    };
+    match network_conditions(&args) {
+        Some(conditions) => ws.set_transport(ConditionedTransport::new(transport, conditions)),
+        None => ws.set_transport(transport),
+    }
+    ws.listen(&args.bind);
+}
+
+// follows max_matches when the config is reloaded, late comers get to watch
+#[cfg(feature = "native")]
+fn cap_clients(config: Res<GameConfig>, mut ws: ResMut<WebsocketServerResource>) {
+    if config.is_changed() {
+        ws.set_max_clients(
+            Some(config.max_players * config.max_matches),
+            Overflow::Spectate,
+        );
+    }
+}
+
+// paddles come from the server, see spawn_paddle_system_client
+#[cfg(feature = "graphics")]
+fn client_startup(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.spawn_bundle(OrthographicCameraBundle::new_2d());
+    commands.spawn_bundle(UiCameraBundle::default());
+
+    commands.spawn_bundle(TextBundle {
+        text: Text {
+            sections: vec![
+                TextSection {
+                    value: "Player: ".to_string(),
+                    style: TextStyle {
+                        font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                        font_size: 40.0,
+                        color: Color::rgb(0.5, 0.5, 1.0),
                     },
-                    TextSection {
-                        value: "0".to_string(), // todo get player number
-                        style: TextStyle {
-                            font: asset_server.load("fonts/FiraSans-Bold.ttf"),
-                            font_size: 40.0,
-                            color: Color::rgb(1.0, 0.5, 0.5),
-                        },
+                },
+                TextSection {
+                    value: "0".to_string(), // todo get player number
+                    style: TextStyle {
+                        font: asset_server.load("fonts/FiraSans-Bold.ttf"),
+                        font_size: 40.0,
+                        color: Color::rgb(1.0, 0.5, 0.5),
                     },
-                ],
-                ..Default::default()
-            },
-            style: Style {
-                position_type: PositionType::Absolute,
-                position: Rect {
-                    top: Val::Px(5.0),
-                    left: Val::Px(5.0),
-                    ..Default::default()
                 },
+            ],
+            ..Default::default()
+        },
+        style: Style {
+            position_type: PositionType::Absolute,
+            position: Rect {
+                top: Val::Px(5.0),
+                left: Val::Px(5.0),
                 ..Default::default()
             },
             ..Default::default()
-        });
-    }
+        },
+        ..Default::default()
+    });
 }
 
-fn is_server() -> bool {
-    let args: Vec<String> = env::args().collect();
-
-    if let Some(arg) = args.get(1) {
-        if arg.eq("--server") {
-            return true;
-        }
-    }
-    return false;
+// `--network-conditions <profile>` simulates a bad connection, see NetworkConditions::PROFILES
+#[cfg(feature = "native")]
+fn network_conditions(args: &Args) -> Option<NetworkConditions> {
+    let conditions = args.network_conditions.clone()?;
+    info!("Simulating network conditions {:?}", conditions);
+    Some(conditions)
 }
 
+#[cfg(feature = "graphics")]
 fn create_network_event_from_keyboard_input(
     time: Res<Time>,
     mut ten_times_per_second: Local<TenTimesPerSecond>,
@@ -279,34 +550,52 @@ fn create_network_event_from_keyboard_input(
     }
 }
 
+#[cfg(feature = "graphics")]
 fn handle_packets_client(
+    net: Res<WebsocketResource>,
+    args: Res<Args>,
     mut network_event_reader: EventReader<WebsocketClientEvent>,
     mut query_to_move_paddles: Query<(&Paddle, &mut Transform, &ControlledByPlayer)>,
     mut player_events: EventWriter<PlayerEcsEvent>,
     mut player_client_id: ResMut<PlayerId>,
+    mut rules: ResMut<Rules>,
 ) {
     for event in network_event_reader.iter() {
         let event: &WebsocketClientEvent = event;
         match event {
             WebsocketClientEvent::OnMessage(msg) => {
                 info!("Received msg: {}", msg);
-                let server_message: ServerMessage =
-                    serde_json::from_str(msg).expect("unable to deserialize json");
+                let server_message: ServerMessage = match serde_json::from_str(msg) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        warn!("Ignoring bad message from the server:{:?}", e);
+                        continue;
+                    }
+                };
                 match server_message {
                     ServerMessage::PlayerStateUpdate(move_paddle) => {
-                        let player_id = move_paddle.player_id;
-                        for (_paddle, mut transform, controlled_by_player) in
-                            query_to_move_paddles.iter_mut()
-                        {
-                            if controlled_by_player.player_id != player_id {
-                                continue;
+                        update_paddle(&mut query_to_move_paddles, &move_paddle);
+                    }
+                    ServerMessage::Snapshot(paddles) => {
+                        // paddles of another match, like the one a spectator was moved from
+                        for (_, _, controlled_by_player) in query_to_move_paddles.iter() {
+                            let player_id = controlled_by_player.player_id;
+                            if !paddles.iter().any(|p| p.player_id == player_id) {
+                                player_events.send(PlayerEcsEvent::Disconnected(player_id));
+                            }
+                        }
+                        for move_paddle in paddles {
+                            if !update_paddle(&mut query_to_move_paddles, &move_paddle) {
+                                player_events
+                                    .send(PlayerEcsEvent::Connected(move_paddle.player_id));
                             }
-                            let translation = &mut transform.translation;
-                            info!(
-                                "Updating paddle posiition from {} to {}",
-                                translation.x, move_paddle.position
-                            );
-                            translation.x = move_paddle.position;
+                        }
+                    }
+                    ServerMessage::Welcome(id) => {
+                        info!("Playing as player {}", id);
+                        player_client_id.0.replace(id);
+                        if let Some(name) = &args.player_name {
+                            send_name(&net, name);
                         }
                     }
                     ServerMessage::PlayerConnected(id) => {
@@ -315,27 +604,116 @@ fn handle_packets_client(
                     ServerMessage::PlayerDisconnected(id) => {
                         player_events.send(PlayerEcsEvent::Disconnected(id));
                     }
+                    ServerMessage::Rules(new_rules) => {
+                        info!("Playing by {:?}", new_rules);
+                        *rules = new_rules;
+                    }
+                    ServerMessage::Queued(status) => match status.estimated_wait {
+                        Some(seconds) => info!(
+                            "Looking for players rated around {}, {} waiting, about {:.0}s left",
+                            status.rating, status.waiting, seconds
+                        ),
+                        None => info!(
+                            "Looking for players rated around {}, {} waiting",
+                            status.rating, status.waiting
+                        ),
+                    },
+                    // see RoomPlugin
+                    ServerMessage::Room(_) | ServerMessage::RoomUnavailable(_) => {}
                 }
             }
 
             WebsocketClientEvent::OnOpen(client_id) => {
-                println!(
-                    "Connected event: is_server: {} got id {}",
-                    is_server(),
-                    client_id
-                );
-                player_client_id.0.replace(*client_id);
+                info!("Connected event: got id {}", client_id);
             }
             WebsocketClientEvent::OnClose => {}
         }
     }
 }
 
-#[cfg(not(feature = "web"))]
+// paddles spawned before the server's rules arrived still have the old speed
+#[cfg(feature = "graphics")]
+fn apply_rules_client(rules: Res<Rules>, mut paddles: Query<&mut Paddle>) {
+    if !rules.is_changed() {
+        return;
+    }
+    for mut paddle in paddles.iter_mut() {
+        paddle.speed = rules.paddle_speed;
+    }
+}
+
+fn send_name(net: &WebsocketResource, name: &str) {
+    let msg = ClientMessage::SetName(name.to_string());
+    let str = serde_json::to_string(&msg).expect("unable to serialize json");
+    net.broadcast(str);
+}
+
+// moves the paddle of `move_paddle.player_id`, false if there's no such paddle
+#[cfg(feature = "graphics")]
+fn update_paddle(
+    query_to_move_paddles: &mut Query<(&Paddle, &mut Transform, &ControlledByPlayer)>,
+    move_paddle: &MovePaddle,
+) -> bool {
+    let mut found = false;
+    for (_paddle, mut transform, controlled_by_player) in query_to_move_paddles.iter_mut() {
+        if controlled_by_player.player_id != move_paddle.player_id {
+            continue;
+        }
+        let translation = &mut transform.translation;
+        info!(
+            "Updating paddle posiition from {} to {}",
+            translation.x, move_paddle.position
+        );
+        translation.x = move_paddle.position;
+        found = true;
+    }
+    found
+}
+
+#[cfg(feature = "native")]
+fn shutdown_on_signal(
+    requested: Res<ShutdownRequested>,
+    mut net: ResMut<WebsocketServerResource>,
+    mut closing: Local<bool>,
+) {
+    if *closing || !requested.0.load(Ordering::SeqCst) {
+        return;
+    }
+    *closing = true;
+    net.close();
+}
+
+#[cfg(feature = "native")]
+fn count_matches(lobby: Res<Lobby>, metrics: Res<Metrics>) {
+    metrics.set_active_matches(lobby.match_count());
+}
+
+#[cfg(feature = "native")]
+fn log_server_traffic(mut diagnostics: EventReader<ServerDiagnostics>) {
+    for diagnostics in diagnostics.iter() {
+        let total = diagnostics.total;
+        info!(
+            "{} clients, {} messages ({} bytes) in, {} messages ({} bytes) out, {} dropped, {} queued",
+            diagnostics.clients.len(),
+            total.messages_in,
+            total.bytes_in,
+            total.messages_out,
+            total.bytes_out,
+            total.dropped,
+            total.queued
+        );
+    }
+}
+
+#[cfg(feature = "native")]
 fn handle_packets_server(
     mut net: ResMut<WebsocketServerResource>,
+    config: Res<GameConfig>,
+    mut lobby: ResMut<Lobby>,
     mut network_event_reader: EventReader<WebsocketServerEvent>,
     mut player_events: EventWriter<PlayerEcsEvent>,
+    mut match_ended: EventWriter<MatchEnded>,
+    mut exit: EventWriter<AppExit>,
 ) {
     for event in network_event_reader.iter() {
         let event: &WebsocketServerEvent = event;
@@ -343,68 +721,580 @@ fn handle_packets_server(
         match event {
             WebsocketServerEvent::OnMessage(client_id, msg) => {
                 info!("Server received message from {}: {}", client_id, msg);
-                let client_message: ClientMessage =
-                    serde_json::from_str(msg).expect("unable to deserialize json");
+                let player_id = match net.client(*client_id).and_then(|c| c.get()) {
+                    Some(PublicPlayerId(id)) => *id,
+                    None => continue,
+                };
+                let match_id = match lobby.match_of(*client_id) {
+                    Some(match_id) => match_id,
+                    None => continue,
+                };
+                // clients can send anything, the rate limit takes care of the ones that keep at it
+                let client_message: ClientMessage = match serde_json::from_str(msg) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        warn!("Ignoring bad message from {}:{:?}", client_id, e);
+                        continue;
+                    }
+                };
+                let is_host = lobby
+                    .get(match_id)
+                    .is_some_and(|game| game.host() == Some(*client_id));
                 match client_message {
-                    ClientMessage::MovePaddle(move_paddle) => {
+                    ClientMessage::MovePaddle(_)
+                        if !lobby.get(match_id).is_some_and(Match::is_started) => {}
+                    ClientMessage::MovePaddle(mut move_paddle) => {
+                        // players only get to move their own paddle
+                        move_paddle.player_id = player_id;
+                        let edge = config.arena.width / 2.0;
+                        move_paddle.position = move_paddle.position.clamp(-edge, edge);
+                        if let Some(client) = net.client_mut(*client_id) {
+                            client.insert(PaddlePosition(move_paddle.position));
+                        }
                         let message = ServerMessage::PlayerStateUpdate(move_paddle);
                         let msg =
                             serde_json::to_string(&message).expect("unable to serialize json");
-                        net.broadcast(msg);
+                        lobby.broadcast(&net, match_id, &msg);
+                    }
+                    ClientMessage::SetName(name) => {
+                        info!(
+                            "Player {} in match {} is called {}",
+                            player_id, match_id, name
+                        );
+                        if let Some(client) = net.client_mut(*client_id) {
+                            client.insert(PlayerName(name));
+                        }
+                    }
+                    ClientMessage::SetRules(rules) => {
+                        let game = match lobby.get_mut(match_id) {
+                            Some(game) if is_host && !game.is_started() => game,
+                            _ => {
+                                warn!("Player {} can't change the rules", player_id);
+                                continue;
+                            }
+                        };
+                        if let Err(e) = validate_rules(&rules) {
+                            warn!("Ignoring rules from player {}: {}", player_id, e);
+                            continue;
+                        }
+                        info!("Match {} plays by {:?}", match_id, rules);
+                        game.rules = rules.clone();
+                        let message = ServerMessage::Rules(rules);
+                        let msg =
+                            serde_json::to_string(&message).expect("unable to serialize json");
+                        lobby.broadcast(&net, match_id, &msg);
+                    }
+                    ClientMessage::StartMatch => {
+                        match lobby.get_mut(match_id) {
+                            Some(game) if is_host && !game.is_started() => game.start(),
+                            _ => {
+                                warn!("Player {} can't start match {}", player_id, match_id);
+                                continue;
+                            }
+                        }
+                        info!("Match {} started", match_id);
+                        broadcast_room(&net, &lobby, match_id);
                     }
                 }
             }
+            WebsocketServerEvent::OnOpen(client_id) if net.is_spectator(*client_id) => {
+                let room = net
+                    .client(*client_id)
+                    .and_then(|client| client.query(ROOM_QUERY_PARAMETER))
+                    .filter(|room| room != NEW_ROOM);
+                match lobby.watch(&mut net, *client_id, room.as_deref()) {
+                    Some(match_id) => {
+                        info!(
+                            "Connected id: {:?} as spectator of match {}, the server is full",
+                            client_id, match_id
+                        );
+                        catch_up(&net, *client_id, None, &lobby, match_id);
+                    }
+                    None => info!("Connected id: {:?} as spectator", client_id),
+                }
+            }
             WebsocketServerEvent::OnOpen(client_id) => {
-                println!("Connected id: {:?}", client_id);
-                player_events.send(PlayerEcsEvent::Connected(*client_id));
-
-                let message = ServerMessage::PlayerConnected(*client_id);
-                let str = serde_json::to_string(&message).expect("unable to serialize json");
-                net.broadcast(str);
+                let room = net
+                    .client(*client_id)
+                    .and_then(|client| client.query(ROOM_QUERY_PARAMETER));
+                let joined = match room.as_deref() {
+                    // the matchmaking queue finds them a match
+                    None => continue,
+                    Some(NEW_ROOM) => Ok(lobby.create_room(&mut net, *client_id, &config.rules)),
+                    Some(code) => lobby.join_room(&mut net, *client_id, code, config.max_players),
+                };
+                let (match_id, player_id) = match joined {
+                    Ok(joined) => joined,
+                    Err(e) => {
+                        warn!("Turning away {}: {}", client_id, e);
+                        let message = ServerMessage::RoomUnavailable(e.to_string());
+                        let str =
+                            serde_json::to_string(&message).expect("unable to serialize json");
+                        net.send(*client_id, str);
+                        net.disconnect(*client_id, &e.to_string());
+                        continue;
+                    }
+                };
+                info!(
+                    "Connected id: {:?} as player {} in match {}",
+                    client_id, player_id, match_id
+                );
+                welcome_player(
+                    &mut net,
+                    &lobby,
+                    *client_id,
+                    match_id,
+                    player_id,
+                    &mut player_events,
+                );
+            }
+            WebsocketServerEvent::LimitExceeded(client_id, limit) => {
+                warn!(
+                    "Dropped message from {} over the {:?} limit",
+                    client_id, limit
+                );
+            }
+            WebsocketServerEvent::OnDisconnect(client_id) => {
+                // the paddle stays where it is until the player comes back or the session expires
+                info!("Client {} lost its connection", client_id);
+            }
+            WebsocketServerEvent::OnResume(client_id) => {
+                let match_id = match lobby.match_of(*client_id) {
+                    Some(match_id) => match_id,
+                    None => continue,
+                };
+                let player_id = net
+                    .client(*client_id)
+                    .and_then(|c| c.get())
+                    .map(|PublicPlayerId(id)| *id);
+                match player_id {
+                    Some(player_id) => info!(
+                        "Client {} is back as player {} in match {}",
+                        client_id, player_id, match_id
+                    ),
+                    None => info!(
+                        "Client {} is back as spectator of match {}",
+                        client_id, match_id
+                    ),
+                }
+                catch_up(&net, *client_id, player_id, &lobby, match_id);
             }
             WebsocketServerEvent::OnClose(client_id) => {
-                println!("Client {} disconnected", client_id);
-                player_events.send(PlayerEcsEvent::Disconnected(*client_id));
+                let match_id = lobby.match_of(*client_id);
+                if let Some(ended) = lobby.leave(&mut net, *client_id) {
+                    for (spectator, watched) in ended.moved {
+                        info!("Spectator {} now watches match {}", spectator, watched);
+                        catch_up(&net, spectator, None, &lobby, watched);
+                    }
+                    match_ended.send(MatchEnded(ended.standings));
+                }
+                let player_id = match net.client(*client_id).and_then(|c| c.get()) {
+                    Some(PublicPlayerId(id)) => *id,
+                    None => continue,
+                };
+                let name = net.client(*client_id).and_then(|c| c.get::<PlayerName>());
+                info!(
+                    "Client {} (player {}, {}) disconnected",
+                    client_id,
+                    player_id,
+                    name.map_or("unnamed", |PlayerName(name)| name)
+                );
+                player_events.send(PlayerEcsEvent::Disconnected(player_id));
 
-                let message = ServerMessage::PlayerDisconnected(*client_id);
+                let message = ServerMessage::PlayerDisconnected(player_id);
                 let str = serde_json::to_string(&message).expect("unable to serialize json");
-                net.broadcast(str);
+                if let Some(match_id) = match_id {
+                    lobby.broadcast(&net, match_id, &str);
+                    // there may be a new host
+                    broadcast_room(&net, &lobby, match_id);
+                }
+            }
+            WebsocketServerEvent::ServerStopped => {
+                info!("Server stopped, exiting");
+                exit.send(AppExit);
             }
         }
     }
 }
 
-#[cfg(not(feature = "web"))]
+// tells a player about the match they're in and everyone in it about the player
+#[cfg(feature = "native")]
+fn welcome_player(
+    net: &mut WebsocketServerResource,
+    lobby: &Lobby,
+    client_id: u64,
+    match_id: u64,
+    player_id: u64,
+    player_events: &mut EventWriter<PlayerEcsEvent>,
+) {
+    // the snapshot has everyone else, the own paddle comes with PlayerConnected
+    send_match(net, client_id, Some(player_id), lobby, match_id);
+    if let Some(client) = net.client_mut(client_id) {
+        client.insert(PublicPlayerId(player_id));
+    }
+    player_events.send(PlayerEcsEvent::Connected(player_id));
+
+    let message = ServerMessage::PlayerConnected(player_id);
+    let str = serde_json::to_string(&message).expect("unable to serialize json");
+    lobby.broadcast(net, match_id, &str);
+    broadcast_room(net, lobby, match_id);
+}
+
+// what a client needs to join or get back into a match, spectators have no player id
+#[cfg(feature = "native")]
+fn send_match(
+    net: &WebsocketServerResource,
+    client_id: u64,
+    player_id: Option<u64>,
+    lobby: &Lobby,
+    match_id: u64,
+) {
+    let game = match lobby.get(match_id) {
+        Some(game) => game,
+        None => return,
+    };
+    let messages = player_id.map(ServerMessage::Welcome).into_iter().chain([
+        ServerMessage::Rules(game.rules.clone()),
+        ServerMessage::Snapshot(paddle_positions(net, game)),
+    ]);
+    for message in messages {
+        let str = serde_json::to_string(&message).expect("unable to serialize json");
+        net.send(client_id, str);
+    }
+}
+
+// the match and who's hosting it, for clients that weren't there when the room was announced
+#[cfg(feature = "native")]
+fn catch_up(
+    net: &WebsocketServerResource,
+    client_id: u64,
+    player_id: Option<u64>,
+    lobby: &Lobby,
+    match_id: u64,
+) {
+    send_match(net, client_id, player_id, lobby, match_id);
+    if let Some(room) = lobby.get(match_id).and_then(|game| room_message(net, game)) {
+        let str = serde_json::to_string(&room).expect("unable to serialize json");
+        net.send(client_id, str);
+    }
+}
+
+// who's hosting a private room and if it started, `None` for public matches
+#[cfg(feature = "native")]
+fn room_message(net: &WebsocketServerResource, game: &Match) -> Option<ServerMessage> {
+    let host = net.client(game.host()?)?;
+    let PublicPlayerId(host) = host.get()?;
+    Some(ServerMessage::Room(Room {
+        code: game.code()?.to_string(),
+        host: *host,
+        started: game.is_started(),
+    }))
+}
+
+#[cfg(feature = "native")]
+fn broadcast_room(net: &WebsocketServerResource, lobby: &Lobby, match_id: u64) {
+    if let Some(room) = lobby.get(match_id).and_then(|game| room_message(net, game)) {
+        let str = serde_json::to_string(&room).expect("unable to serialize json");
+        lobby.broadcast(net, match_id, &str);
+    }
+}
+
+// every paddle in the match, including the ones of players waiting to resume
+#[cfg(feature = "native")]
+fn paddle_positions(net: &WebsocketServerResource, game: &Match) -> Vec<MovePaddle> {
+    game.players()
+        .filter_map(|client_id| {
+            let client = net.client(client_id)?;
+            let PublicPlayerId(player_id) = client.get()?;
+            Some(MovePaddle {
+                player_id: *player_id,
+                position: client.get().map(|PaddlePosition(x)| *x).unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(feature = "native")]
 fn spawn_paddle_system_server(mut events: EventReader<PlayerEcsEvent>) {
     for my_event in events.iter() {
         let my_event: &PlayerEcsEvent = my_event;
         if let &PlayerEcsEvent::Connected(id) = my_event {
-            println!("time to spawn a fucking paddle yo player_id: {}", id);
+            debug!("time to spawn a fucking paddle yo player_id: {}", id);
         }
     }
 }
 
-#[cfg(not(feature = "headless"))]
+#[cfg(feature = "graphics")]
 fn spawn_paddle_system_client(
     mut commands: Commands,
+    rules: Res<Rules>,
     mut events: EventReader<PlayerEcsEvent>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
+    paddles: Query<(Entity, &ControlledByPlayer)>,
 ) {
+    let mut spawned: HashMap<u64, Entity> = paddles
+        .iter()
+        .map(|(entity, paddle)| (paddle.player_id, entity))
+        .collect();
     for my_event in events.iter() {
-        let my_event: &PlayerEcsEvent = my_event;
-        if let &PlayerEcsEvent::Connected(id) = my_event {
-            commands
-                .spawn_bundle(SpriteBundle {
-                    transform: Transform::from_xyz(0.0, id as f32 * 40. - 200., 0.0),
-                    sprite: Sprite {
-                        color: Color::rgb(0.5, 0.5, 1.0),
-                        custom_size: Some(Vec2::new(120.0, 30.0)),
-                        ..default()
-                    },
+        match *my_event {
+            // a player can be both in a snapshot and announced, one paddle each
+            PlayerEcsEvent::Connected(id) if !spawned.contains_key(&id) => {
+                let entity = commands
+                    .spawn_bundle(SpriteBundle {
+                        transform: Transform::from_xyz(0.0, id as f32 * 40. - 200., 0.0),
+                        sprite: Sprite {
+                            color: Color::rgb(0.5, 0.5, 1.0),
+                            custom_size: Some(Vec2::new(120.0, 30.0)),
+                            ..default()
+                        },
+                        ..Default::default()
+                    })
+                    .insert(Paddle {
+                        speed: rules.paddle_speed,
+                    })
+                    .insert(ControlledByPlayer { player_id: id })
+                    .id();
+                spawned.insert(id, entity);
+            }
+            PlayerEcsEvent::Disconnected(id) => {
+                if let Some(entity) = spawned.remove(&id) {
+                    commands.entity(entity).despawn();
+                }
+            }
+            PlayerEcsEvent::Connected(_) => {}
+        }
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use bevy::ecs::event::{Events, ManualEventReader};
+    use bevy_ws::LoopbackTransport;
+
+    use super::*;
+    use crate::config::Matchmaking;
+
+    // steps both apps until the client gets a message `accept` returns something for
+    fn next_message<T>(
+        server: &mut App,
+        client: &mut App,
+        reader: &mut ManualEventReader<WebsocketClientEvent>,
+        accept: impl Fn(ServerMessage) -> Option<T>,
+    ) -> T {
+        // long enough for the matchmaking to come around
+        for _ in 0..3000 {
+            server.update();
+            client.update();
+            let events = client.world.resource::<Events<WebsocketClientEvent>>();
+            for event in reader.iter(events) {
+                if let WebsocketClientEvent::OnMessage(msg) = event {
+                    if let Some(found) = serde_json::from_str(msg).ok().and_then(&accept) {
+                        return found;
+                    }
+                }
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        panic!("the message never arrived");
+    }
+
+    #[test]
+    fn bad_messages_from_clients_are_ignored() {
+        let loopback = LoopbackTransport::default();
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .init_resource::<GameConfig>()
+            .init_resource::<Lobby>()
+            .add_event::<PlayerEcsEvent>()
+            .add_event::<MatchEnded>()
+            .add_system(handle_packets_server);
+        let mut ws = server.world.resource_mut::<WebsocketServerResource>();
+        ws.set_transport(loopback.clone());
+        ws.listen("localhost:8080");
+        server.update();
+
+        let mut client = App::new();
+        client
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin);
+        let mut ws = client.world.resource_mut::<WebsocketResource>();
+        ws.set_transport(loopback);
+        ws.open("ws://localhost:8080/?room=new");
+        let mut reader = ManualEventReader::default();
+        let room = |message| match message {
+            ServerMessage::Room(room) => Some(room),
+            _ => None,
+        };
+        let waiting = next_message(&mut server, &mut client, &mut reader, room);
+        assert!(!waiting.started);
+
+        let ws = client.world.resource::<WebsocketResource>();
+        for bad in [
+            "not json",
+            "{\"StartMatch\":",
+            "{\"Teleport\":[1,2]}",
+            "\"StartMatch\"",
+        ] {
+            ws.broadcast(bad.to_string());
+        }
+        let started = next_message(&mut server, &mut client, &mut reader, room);
+        assert_eq!(started.code, waiting.code);
+        assert!(started.started);
+    }
+
+    fn open_client(loopback: &LoopbackTransport, address: &str) -> App {
+        let mut client = App::new();
+        client
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketPlugin);
+        let mut ws = client.world.resource_mut::<WebsocketResource>();
+        ws.set_transport(loopback.clone());
+        ws.open(address);
+        client
+    }
+
+    #[test]
+    fn spectators_move_on_when_their_match_ends() {
+        let loopback = LoopbackTransport::default();
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            // a match for each of the two players, the third one watches
+            .insert_resource(GameConfig {
+                max_players: 1,
+                max_matches: 2,
+                matchmaking: Matchmaking {
+                    min_players: 1,
                     ..Default::default()
-                })
-                .insert(Paddle { speed: 500.0 })
-                .insert(ControlledByPlayer { player_id: id });
+                },
+                ..Default::default()
+            })
+            .init_resource::<Lobby>()
+            .init_resource::<Ratings>()
+            .add_event::<PlayerEcsEvent>()
+            .add_plugin(MatchmakingPlugin)
+            .add_system(handle_packets_server)
+            .add_system(cap_clients);
+        let mut ws = server.world.resource_mut::<WebsocketServerResource>();
+        ws.set_transport(loopback.clone());
+        ws.listen("localhost:8080");
+        server.update();
+
+        let welcome = |message| match message {
+            ServerMessage::Welcome(player_id) => Some(player_id),
+            _ => None,
+        };
+        let mut first = open_client(&loopback, "ws://localhost:8080/");
+        next_message(
+            &mut server,
+            &mut first,
+            &mut ManualEventReader::default(),
+            welcome,
+        );
+        let mut second = open_client(&loopback, "ws://localhost:8080/");
+        next_message(
+            &mut server,
+            &mut second,
+            &mut ManualEventReader::default(),
+            welcome,
+        );
+
+        let mut spectator = open_client(&loopback, "ws://localhost:8080/");
+        let mut reader = ManualEventReader::default();
+        let rules = |message| match message {
+            ServerMessage::Rules(rules) => Some(rules),
+            _ => None,
+        };
+        next_message(&mut server, &mut spectator, &mut reader, rules);
+        let snapshot = |message| match message {
+            ServerMessage::Snapshot(paddles) => Some(paddles),
+            _ => None,
+        };
+        assert_eq!(
+            next_message(&mut server, &mut spectator, &mut reader, snapshot).len(),
+            1
+        );
+
+        // the first match ends, the spectator is told about the second
+        first.world.resource::<WebsocketResource>().close();
+        next_message(&mut server, &mut spectator, &mut reader, rules);
+        assert_eq!(
+            next_message(&mut server, &mut spectator, &mut reader, snapshot).len(),
+            1
+        );
+        let lobby = server.world.resource::<Lobby>();
+        assert_eq!(lobby.match_count(), 1);
+    }
+
+    #[test]
+    fn finished_matches_are_rated() {
+        let dir = std::env::temp_dir();
+        let accounts = dir.join("pong-royale-rated-accounts.toml");
+        std::fs::write(&accounts, "alice = \"a\"\nbob = \"b\"\n").unwrap();
+        let ratings_file = dir.join("pong-royale-rated-ratings.json");
+        let _ = std::fs::remove_file(&ratings_file);
+
+        let loopback = LoopbackTransport::default();
+        let mut server = App::new();
+        server
+            .add_plugins(MinimalPlugins)
+            .add_plugin(WebsocketServerPlugin)
+            .insert_resource(GameConfig {
+                max_players: 2,
+                ..Default::default()
+            })
+            .insert_resource(Ratings::load(&ratings_file).unwrap())
+            .insert_resource(load_accounts(accounts.to_str().unwrap()))
+            .init_resource::<Lobby>()
+            .add_event::<PlayerEcsEvent>()
+            .add_plugin(MatchmakingPlugin)
+            .add_startup_system(require_accounts)
+            .add_system(handle_packets_server);
+        let mut ws = server.world.resource_mut::<WebsocketServerResource>();
+        ws.set_transport(loopback.clone());
+        ws.listen("localhost:8080");
+        server.update();
+
+        let welcome = |message| match message {
+            ServerMessage::Welcome(player_id) => Some(player_id),
+            _ => None,
+        };
+        let queued = |message| match message {
+            ServerMessage::Queued(status) => Some(status),
+            _ => None,
+        };
+        let mut alice = open_client(&loopback, "ws://localhost:8080/?player=alice&key=a");
+        let mut alice_reader = ManualEventReader::default();
+        next_message(&mut server, &mut alice, &mut alice_reader, queued);
+        let mut bob = open_client(&loopback, "ws://localhost:8080/?player=bob&key=b");
+        next_message(
+            &mut server,
+            &mut bob,
+            &mut ManualEventReader::default(),
+            welcome,
+        );
+        next_message(&mut server, &mut alice, &mut alice_reader, welcome);
+
+        // alice leaves first and loses, the match ends when bob leaves too
+        alice.world.resource::<WebsocketResource>().close();
+        for _ in 0..10 {
+            alice.update();
+            server.update();
+        }
+        bob.world.resource::<WebsocketResource>().close();
+        for _ in 0..3000 {
+            bob.update();
+            server.update();
+            if ratings_file.exists() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
         }
+        let saved = Ratings::load(&ratings_file).unwrap();
+        assert!(saved.get(Some("bob")) > saved.get(Some("alice")));
+        assert!(saved.get(None) > saved.get(Some("alice")));
     }
 }
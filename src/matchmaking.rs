@@ -0,0 +1,535 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+use bevy_ws::{Identity, WebsocketServerEvent, WebsocketServerResource};
+use log::{info, warn};
+
+use crate::config::{GameConfig, Matchmaking};
+use crate::lobby::Lobby;
+use crate::network::{QueueStatus, ServerMessage, ROOM_QUERY_PARAMETER};
+use crate::{welcome_player, PlayerEcsEvent};
+
+// what players start with
+const INITIAL_RATING: f64 = 1500.0;
+// the most a rating moves in one match
+const K_FACTOR: f64 = 32.0;
+// how often the queue looks for matches
+const MATCHMAKING_INTERVAL: f32 = 1.0;
+// how often queued players hear how long they'll wait
+const STATUS_INTERVAL: f32 = 5.0;
+// how many recent waits the estimate is based on
+const RECENT_WAITS: usize = 32;
+
+/// Queues players who connect without a room, starts public matches of players with similar
+/// ratings and rates everyone by the order they left their match in
+pub struct MatchmakingPlugin;
+
+impl Plugin for MatchmakingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Queue>();
+        app.add_event::<MatchEnded>();
+        app.add_system(queue_players);
+        app.add_system(match_queued_players);
+        app.add_system(send_queue_status);
+        app.add_system(rate_players);
+    }
+}
+
+/// A public match ended, with its rated players best first, see [`Lobby::leave`]
+pub struct MatchEnded(pub Vec<String>);
+
+/// Elo ratings by authenticated identity. A match counts as a win against everyone who left
+/// it earlier and a loss against everyone who stayed longer
+#[derive(Default)]
+pub struct Ratings {
+    ratings: BTreeMap<String, f64>,
+    path: Option<PathBuf>,
+}
+
+impl Ratings {
+    /// The ratings saved at `path`, none if there's no file yet. They're saved there after
+    /// every rated match
+    pub fn load(path: &Path) -> Result<Self, RatingsError> {
+        let ratings = if path.exists() {
+            let text = std::fs::read_to_string(path).map_err(RatingsError::Io)?;
+            serde_json::from_str(&text).map_err(RatingsError::Parse)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self {
+            ratings,
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    /// The rating of `key`, anonymous and new players get the initial one
+    pub fn get(&self, key: Option<&str>) -> f64 {
+        key.and_then(|key| self.ratings.get(key))
+            .copied()
+            .unwrap_or(INITIAL_RATING)
+    }
+
+    /// Rates the players in `standings`, best first
+    pub fn record(&mut self, standings: &[String]) {
+        // someone playing twice at once counts where they placed best
+        let mut seen = HashSet::new();
+        let standings: Vec<&String> = standings.iter().filter(|key| seen.insert(*key)).collect();
+        if standings.len() < 2 {
+            return;
+        }
+        let before: Vec<f64> = standings.iter().map(|key| self.get(Some(key))).collect();
+        // a match moves ratings as much as a game of two would, however many played
+        let k = K_FACTOR / (standings.len() - 1) as f64;
+        for (i, key) in standings.iter().enumerate() {
+            let change: f64 = before
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(j, other)| {
+                    let expected = 1.0 / (1.0 + 10f64.powf((other - before[i]) / 400.0));
+                    let score = if i < j { 1.0 } else { 0.0 };
+                    k * (score - expected)
+                })
+                .sum();
+            self.ratings.insert(key.to_string(), before[i] + change);
+        }
+    }
+
+    fn save(&self) -> Result<(), std::io::Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let text = serde_json::to_string_pretty(&self.ratings).expect("unable to serialize json");
+        std::fs::write(path, text)
+    }
+}
+
+#[derive(Debug)]
+pub enum RatingsError {
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+}
+
+impl fmt::Display for RatingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RatingsError::Io(e) => write!(f, "can't read ratings: {}", e),
+            RatingsError::Parse(e) => write!(f, "bad ratings: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RatingsError {}
+
+/// Players waiting for a public match, oldest first
+#[derive(Default)]
+pub struct Queue {
+    waiting: Vec<Queued>,
+    // the rating of recently matched players and the seconds they waited
+    recent: VecDeque<(f64, f64)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Queued {
+    pub client_id: u64,
+    pub rating: f64,
+    pub key: Option<String>,
+    since: f64,
+}
+
+/// Where the queue puts its players
+pub enum Pairing {
+    /// into a public match with room for them
+    Join(u64, Queued),
+    /// together into a new match
+    Open(Vec<Queued>),
+}
+
+impl Queue {
+    pub fn push(&mut self, client_id: u64, rating: f64, key: Option<String>, now: f64) {
+        self.waiting.push(Queued {
+            client_id,
+            rating,
+            key,
+            since: now,
+        });
+    }
+
+    pub fn remove(&mut self, client_id: u64) {
+        self.waiting.retain(|queued| queued.client_id != client_id);
+    }
+
+    /// Finds the oldest player a place among `open`, the public matches with room and their
+    /// average rating, or else among the other queued players when `can_open` a match.
+    /// Everyone only plays with ratings inside their window, which widens as they wait
+    pub fn next(
+        &mut self,
+        settings: &Matchmaking,
+        max_players: usize,
+        now: f64,
+        open: &[(u64, f64)],
+        can_open: bool,
+    ) -> Option<Pairing> {
+        for i in 0..self.waiting.len() {
+            let anchor = self.waiting[i].clone();
+            let reach = window(settings, &anchor, now);
+            let distance = |rating: f64| (rating - anchor.rating).abs();
+            let nearest = open
+                .iter()
+                .filter(|(_, skill)| distance(*skill) <= reach)
+                .min_by(|(_, a), (_, b)| distance(*a).total_cmp(&distance(*b)));
+            if let Some((match_id, _)) = nearest {
+                let queued = self.waiting.remove(i);
+                self.record_wait(&queued, now);
+                return Some(Pairing::Join(*match_id, queued));
+            }
+            if !can_open {
+                continue;
+            }
+            let mut group: Vec<&Queued> = self
+                .waiting
+                .iter()
+                .filter(|other| {
+                    let apart = distance(other.rating);
+                    apart <= reach && apart <= window(settings, other, now)
+                })
+                .collect();
+            if group.len() < settings.min_players {
+                continue;
+            }
+            group.sort_by(|a, b| distance(a.rating).total_cmp(&distance(b.rating)));
+            group.truncate(max_players);
+            let group: Vec<Queued> = group.into_iter().cloned().collect();
+            self.waiting
+                .retain(|queued| group.iter().all(|q| q.client_id != queued.client_id));
+            for queued in &group {
+                self.record_wait(queued, now);
+            }
+            return Some(Pairing::Open(group));
+        }
+        None
+    }
+
+    /// Seconds until `queued` likely gets a match, by how long players of similar skill
+    /// waited lately. `None` before anyone got one
+    pub fn estimate(&self, settings: &Matchmaking, queued: &Queued, now: f64) -> Option<f64> {
+        let similar: Vec<f64> = self
+            .recent
+            .iter()
+            .filter(|(rating, _)| (rating - queued.rating).abs() <= settings.window)
+            .map(|(_, waited)| *waited)
+            .collect();
+        let waits = if similar.is_empty() {
+            self.recent.iter().map(|(_, waited)| *waited).collect()
+        } else {
+            similar
+        };
+        if waits.is_empty() {
+            return None;
+        }
+        let average = waits.iter().sum::<f64>() / waits.len() as f64;
+        Some((average - (now - queued.since)).max(0.0))
+    }
+
+    fn record_wait(&mut self, queued: &Queued, now: f64) {
+        if self.recent.len() == RECENT_WAITS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((queued.rating, now - queued.since));
+    }
+}
+
+// how far from their own the ratings `queued` plays with can be by now
+fn window(settings: &Matchmaking, queued: &Queued, now: f64) -> f64 {
+    settings.window + settings.window_growth * (now - queued.since)
+}
+
+fn send_status(
+    net: &WebsocketServerResource,
+    settings: &Matchmaking,
+    queue: &Queue,
+    queued: &Queued,
+    now: f64,
+) {
+    let message = ServerMessage::Queued(QueueStatus {
+        rating: queued.rating.max(0.0).round() as u32,
+        waiting: queue.waiting.len(),
+        estimated_wait: queue
+            .estimate(settings, queued, now)
+            .map(|seconds| seconds as f32),
+    });
+    let str = serde_json::to_string(&message).expect("unable to serialize json");
+    net.send(queued.client_id, str);
+}
+
+// players without a room wait here for a public match. Only who they authenticated as is rated,
+// see `--accounts`, anyone can claim a name so anonymous players queue with the initial rating
+// and stay there
+fn queue_players(
+    time: Res<Time>,
+    net: Res<WebsocketServerResource>,
+    config: Res<GameConfig>,
+    ratings: Res<Ratings>,
+    mut queue: ResMut<Queue>,
+    mut network_event_reader: EventReader<WebsocketServerEvent>,
+) {
+    let now = time.seconds_since_startup();
+    for event in network_event_reader.iter() {
+        match event {
+            WebsocketServerEvent::OnOpen(client_id) if !net.is_spectator(*client_id) => {
+                let client = match net.client(*client_id) {
+                    Some(client) => client,
+                    None => continue,
+                };
+                // private rooms skip the queue
+                if client.query(ROOM_QUERY_PARAMETER).is_some() {
+                    continue;
+                }
+                let key = client
+                    .identity
+                    .as_ref()
+                    .map(|Identity(identity)| identity.clone());
+                let rating = ratings.get(key.as_deref());
+                info!(
+                    "Queued {} ({}) with rating {:.0}",
+                    client_id,
+                    key.as_deref().unwrap_or("anonymous"),
+                    rating
+                );
+                queue.push(*client_id, rating, key, now);
+                if let Some(queued) = queue.waiting.last() {
+                    send_status(&net, &config.matchmaking, &queue, queued, now);
+                }
+            }
+            WebsocketServerEvent::OnClose(client_id) => queue.remove(*client_id),
+            _ => {}
+        }
+    }
+}
+
+fn match_queued_players(
+    time: Res<Time>,
+    config: Res<GameConfig>,
+    mut net: ResMut<WebsocketServerResource>,
+    mut lobby: ResMut<Lobby>,
+    mut queue: ResMut<Queue>,
+    mut player_events: EventWriter<PlayerEcsEvent>,
+    mut check: Local<Option<Timer>>,
+) {
+    let check = check.get_or_insert_with(|| Timer::from_seconds(MATCHMAKING_INTERVAL, true));
+    if !check.tick(time.delta()).just_finished() {
+        return;
+    }
+    let now = time.seconds_since_startup();
+    loop {
+        let open = lobby.public_matches(config.max_players);
+        let can_open = lobby.match_count() < config.max_matches;
+        let pairing = queue.next(
+            &config.matchmaking,
+            config.max_players,
+            now,
+            &open,
+            can_open,
+        );
+        let (match_id, players) = match pairing {
+            Some(Pairing::Join(match_id, queued)) => (match_id, vec![queued]),
+            Some(Pairing::Open(players)) => (lobby.open_public(&config.rules), players),
+            None => break,
+        };
+        for queued in players {
+            let player_id = lobby.join(
+                &mut net,
+                match_id,
+                queued.client_id,
+                queued.rating,
+                queued.key,
+            );
+            info!(
+                "Connected id: {:?} as player {} in match {}, rated {:.0}",
+                queued.client_id, player_id, match_id, queued.rating
+            );
+            welcome_player(
+                &mut net,
+                &lobby,
+                queued.client_id,
+                match_id,
+                player_id,
+                &mut player_events,
+            );
+        }
+    }
+}
+
+fn send_queue_status(
+    time: Res<Time>,
+    config: Res<GameConfig>,
+    net: Res<WebsocketServerResource>,
+    queue: Res<Queue>,
+    mut check: Local<Option<Timer>>,
+) {
+    let check = check.get_or_insert_with(|| Timer::from_seconds(STATUS_INTERVAL, true));
+    if !check.tick(time.delta()).just_finished() {
+        return;
+    }
+    let now = time.seconds_since_startup();
+    for queued in &queue.waiting {
+        send_status(&net, &config.matchmaking, &queue, queued, now);
+    }
+}
+
+fn rate_players(mut ratings: ResMut<Ratings>, mut match_ended: EventReader<MatchEnded>) {
+    for MatchEnded(standings) in match_ended.iter() {
+        ratings.record(standings);
+        for key in standings {
+            info!("{} is now rated {:.0}", key, ratings.get(Some(key)));
+        }
+        if let Err(e) = ratings.save() {
+            warn!("Saving ratings failed:{:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Matchmaking {
+        Matchmaking {
+            min_players: 2,
+            window: 100.0,
+            window_growth: 10.0,
+            ratings_file: None,
+        }
+    }
+
+    fn standings(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    fn opened(pairing: Option<Pairing>) -> Vec<u64> {
+        match pairing {
+            Some(Pairing::Open(players)) => players.iter().map(|q| q.client_id).collect(),
+            _ => panic!("expected a new match"),
+        }
+    }
+
+    #[test]
+    fn placement_decides_who_gains() {
+        let mut ratings = Ratings::default();
+        ratings.record(&standings(&["alice", "bob", "carol"]));
+        let (alice, bob, carol) = (
+            ratings.get(Some("alice")),
+            ratings.get(Some("bob")),
+            ratings.get(Some("carol")),
+        );
+        assert!(alice > bob && bob > carol);
+        assert!((bob - INITIAL_RATING).abs() < 1e-9);
+        assert!((alice + bob + carol - 3.0 * INITIAL_RATING).abs() < 1e-9);
+    }
+
+    #[test]
+    fn beating_a_stronger_player_gains_more() {
+        let mut ratings = Ratings::default();
+        ratings.ratings.insert("strong".to_string(), 1900.0);
+        ratings.record(&standings(&["weak", "strong"]));
+        let upset = ratings.get(Some("weak")) - INITIAL_RATING;
+
+        let mut ratings = Ratings::default();
+        ratings.record(&standings(&["alice", "bob"]));
+        let even = ratings.get(Some("alice")) - INITIAL_RATING;
+        assert!(upset > even && even > 0.0);
+    }
+
+    #[test]
+    fn lone_and_repeated_players_count_once() {
+        let mut ratings = Ratings::default();
+        ratings.record(&standings(&["alice"]));
+        assert_eq!(ratings.get(Some("alice")), INITIAL_RATING);
+        ratings.record(&standings(&["alice", "alice"]));
+        assert_eq!(ratings.get(Some("alice")), INITIAL_RATING);
+        ratings.record(&standings(&["alice", "bob", "alice"]));
+        assert!(ratings.get(Some("alice")) > INITIAL_RATING);
+        assert_eq!(ratings.get(None), INITIAL_RATING);
+    }
+
+    #[test]
+    fn windows_widen_while_waiting() {
+        let mut queue = Queue::default();
+        queue.push(1, 1500.0, None, 0.0);
+        queue.push(2, 1800.0, None, 0.0);
+        assert!(queue.next(&settings(), 16, 0.0, &[], true).is_none());
+        // 100 + 10 * 19 is still short of 300 apart
+        assert!(queue.next(&settings(), 16, 19.0, &[], true).is_none());
+        assert_eq!(opened(queue.next(&settings(), 16, 20.0, &[], true)), [1, 2]);
+        assert!(queue.next(&settings(), 16, 20.0, &[], true).is_none());
+    }
+
+    #[test]
+    fn both_players_have_to_accept_each_other() {
+        let mut queue = Queue::default();
+        queue.push(1, 1500.0, None, 0.0);
+        queue.push(2, 1750.0, None, 10.0);
+        // the first one's window reaches, the newcomer's doesn't yet
+        assert!(queue.next(&settings(), 16, 15.0, &[], true).is_none());
+        assert_eq!(opened(queue.next(&settings(), 16, 25.0, &[], true)), [1, 2]);
+    }
+
+    #[test]
+    fn closest_players_fill_a_match_first() {
+        let mut queue = Queue::default();
+        queue.push(1, 1500.0, None, 0.0);
+        queue.push(2, 1590.0, None, 0.0);
+        queue.push(3, 1510.0, None, 0.0);
+        queue.push(4, 1450.0, None, 0.0);
+        assert_eq!(
+            opened(queue.next(&settings(), 3, 0.0, &[], true)),
+            [1, 3, 4]
+        );
+    }
+
+    #[test]
+    fn open_matches_of_similar_skill_come_first() {
+        let mut queue = Queue::default();
+        queue.push(1, 1500.0, None, 0.0);
+        queue.push(2, 1500.0, None, 0.0);
+        let open = [(7, 1900.0), (8, 1560.0), (9, 1540.0)];
+        match queue.next(&settings(), 16, 0.0, &open, true) {
+            Some(Pairing::Join(match_id, queued)) => {
+                assert_eq!((match_id, queued.client_id), (9, 1));
+            }
+            _ => panic!("expected to join match 9"),
+        }
+    }
+
+    #[test]
+    fn no_new_matches_past_the_limit() {
+        let mut queue = Queue::default();
+        queue.push(1, 1500.0, None, 0.0);
+        queue.push(2, 1500.0, None, 0.0);
+        assert!(queue.next(&settings(), 16, 0.0, &[], false).is_none());
+        assert_eq!(opened(queue.next(&settings(), 16, 0.0, &[], true)), [1, 2]);
+    }
+
+    #[test]
+    fn estimate_follows_recent_waits_of_similar_players() {
+        let mut queue = Queue::default();
+        queue.push(1, 1500.0, None, 0.0);
+        queue.push(2, 1500.0, None, 10.0);
+        let waiting = queue.waiting[0].clone();
+        assert_eq!(queue.estimate(&settings(), &waiting, 0.0), None);
+        opened(queue.next(&settings(), 16, 20.0, &[], true));
+        // they waited 20 and 10 seconds
+        queue.push(3, 1520.0, None, 20.0);
+        let similar = queue.waiting[0].clone();
+        assert_eq!(queue.estimate(&settings(), &similar, 20.0), Some(15.0));
+        assert_eq!(queue.estimate(&settings(), &similar, 25.0), Some(10.0));
+        assert_eq!(queue.estimate(&settings(), &similar, 60.0), Some(0.0));
+
+        queue.push(4, 2400.0, None, 20.0);
+        let other = queue.waiting[1].clone();
+        assert_eq!(queue.estimate(&settings(), &other, 20.0), Some(15.0));
+    }
+}
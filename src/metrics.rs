@@ -0,0 +1,216 @@
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+use bevy_ws::{ConnectionCount, TrafficStats, WebsocketServerResource};
+
+use crate::http::{Response, Routes};
+
+// how often the traffic counters are sampled for the per second rates
+const SAMPLE_INTERVAL: f32 = 1.0;
+
+/// Serves what the server is doing in the Prometheus text format on `GET /metrics`,
+/// see [`crate::http::HttpPlugin`]
+pub struct MetricsPlugin;
+
+impl Plugin for MetricsPlugin {
+    fn build(&self, app: &mut App) {
+        let metrics = Metrics::default();
+        let serving = metrics.clone();
+        app.world
+            .get_resource_or_insert_with(Routes::default)
+            .add("/metrics", move || {
+                let mut snapshot = serving.0.lock().expect("lock");
+                let body = snapshot.render();
+                // every scrape sees the longest frame since the one before
+                snapshot.max_tick_seconds = 0.0;
+                Response {
+                    content_type: "text/plain; version=0.0.4",
+                    ..Response::ok(body)
+                }
+            });
+        app.insert_resource(metrics);
+        app.add_system_to_stage(CoreStage::Last, sample_tick_duration);
+        app.add_system(sample_connections);
+    }
+}
+
+/// The numbers behind `/metrics`, game systems fill in what bevy_ws doesn't know
+#[derive(Default, Clone)]
+pub struct Metrics(Arc<Mutex<Snapshot>>);
+
+impl Metrics {
+    pub fn set_active_matches(&self, matches: usize) {
+        self.0.lock().expect("lock").active_matches = matches;
+    }
+}
+
+#[derive(Default)]
+struct Snapshot {
+    players: usize,
+    spectators: usize,
+    active_matches: usize,
+    tick_seconds: f64,
+    max_tick_seconds: f64,
+    traffic: TrafficStats,
+    messages_in_per_second: f64,
+    bytes_in_per_second: f64,
+    messages_out_per_second: f64,
+    bytes_out_per_second: f64,
+}
+
+impl Snapshot {
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        metric(
+            "pong_connected_players",
+            "gauge",
+            "Connected players, spectators and players waiting to resume not included",
+            self.players as f64,
+        );
+        metric(
+            "pong_connected_spectators",
+            "gauge",
+            "Connected spectators, not the ones waiting to resume",
+            self.spectators as f64,
+        );
+        metric(
+            "pong_active_matches",
+            "gauge",
+            "Matches being played",
+            self.active_matches as f64,
+        );
+        metric(
+            "pong_tick_duration_seconds",
+            "gauge",
+            "Time the last frame spent running its systems",
+            self.tick_seconds,
+        );
+        metric(
+            "pong_max_tick_duration_seconds",
+            "gauge",
+            "Longest frame since the last scrape",
+            self.max_tick_seconds,
+        );
+        metric(
+            "pong_messages_received_total",
+            "counter",
+            "Websocket messages received from clients",
+            self.traffic.messages_in as f64,
+        );
+        metric(
+            "pong_bytes_received_total",
+            "counter",
+            "Websocket payload bytes received from clients",
+            self.traffic.bytes_in as f64,
+        );
+        metric(
+            "pong_messages_sent_total",
+            "counter",
+            "Websocket messages sent to clients",
+            self.traffic.messages_out as f64,
+        );
+        metric(
+            "pong_bytes_sent_total",
+            "counter",
+            "Websocket payload bytes sent to clients",
+            self.traffic.bytes_out as f64,
+        );
+        metric(
+            "pong_messages_received_per_second",
+            "gauge",
+            "Websocket messages received from clients over the last second",
+            self.messages_in_per_second,
+        );
+        metric(
+            "pong_bytes_received_per_second",
+            "gauge",
+            "Websocket payload bytes received from clients over the last second",
+            self.bytes_in_per_second,
+        );
+        metric(
+            "pong_messages_sent_per_second",
+            "gauge",
+            "Websocket messages sent to clients over the last second",
+            self.messages_out_per_second,
+        );
+        metric(
+            "pong_bytes_sent_per_second",
+            "gauge",
+            "Websocket payload bytes sent to clients over the last second",
+            self.bytes_out_per_second,
+        );
+        metric(
+            "pong_dropped_messages_total",
+            "counter",
+            "Messages thrown away for going over a limit or to a closed connection",
+            self.traffic.dropped as f64,
+        );
+        metric(
+            "pong_queued_messages",
+            "gauge",
+            "Messages waiting to be sent to clients",
+            self.traffic.queued as f64,
+        );
+        out
+    }
+}
+
+// from the start of the frame, the runner's sleep between frames isn't part of it
+fn sample_tick_duration(time: Res<Time>, metrics: Res<Metrics>) {
+    let tick = match time.last_update() {
+        Some(started) => started.elapsed().as_secs_f64(),
+        None => return,
+    };
+    let mut snapshot = metrics.0.lock().expect("lock");
+    snapshot.tick_seconds = tick;
+    snapshot.max_tick_seconds = snapshot.max_tick_seconds.max(tick);
+}
+
+fn sample_connections(
+    time: Res<Time>,
+    server: Res<WebsocketServerResource>,
+    connections: Res<ConnectionCount>,
+    metrics: Res<Metrics>,
+    mut sample: Local<Option<Timer>>,
+    mut sampled_at: Local<f64>,
+) {
+    let sample = sample.get_or_insert_with(|| Timer::from_seconds(SAMPLE_INTERVAL, true));
+    if !sample.tick(time.delta()).just_finished() {
+        return;
+    }
+    let now = time.seconds_since_startup();
+    let elapsed = now - std::mem::replace(&mut *sampled_at, now);
+    let traffic = server.total_traffic();
+    let mut snapshot = metrics.0.lock().expect("lock");
+    let per_second = |now: u64, before: u64| {
+        // the counters start over when the server listens again
+        let before = if now < before { 0 } else { before };
+        now.saturating_sub(before) as f64 / elapsed.max(f64::EPSILON)
+    };
+    snapshot.messages_in_per_second = per_second(traffic.messages_in, snapshot.traffic.messages_in);
+    snapshot.bytes_in_per_second = per_second(traffic.bytes_in, snapshot.traffic.bytes_in);
+    snapshot.messages_out_per_second =
+        per_second(traffic.messages_out, snapshot.traffic.messages_out);
+    snapshot.bytes_out_per_second = per_second(traffic.bytes_out, snapshot.traffic.bytes_out);
+    snapshot.traffic = traffic;
+    // suspended clients keep their place but aren't connected
+    let (mut suspended_players, mut suspended_spectators) = (0, 0);
+    for (client_id, _) in server.clients() {
+        match (
+            server.is_suspended(client_id),
+            server.is_spectator(client_id),
+        ) {
+            (true, false) => suspended_players += 1,
+            (true, true) => suspended_spectators += 1,
+            _ => {}
+        }
+    }
+    snapshot.players = connections.players.saturating_sub(suspended_players);
+    snapshot.spectators = connections.spectators.saturating_sub(suspended_spectators);
+}
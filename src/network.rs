@@ -7,6 +7,15 @@ use serde::Serialize;
 //     MovePaddle(MovePaddle),
 // }
 
+// the query string parameter of the game socket with the private room to join
+pub const ROOM_QUERY_PARAMETER: &str = "room";
+// the query string parameters of the game socket a player with an account logs in with
+pub const PLAYER_QUERY_PARAMETER: &str = "player";
+pub const KEY_QUERY_PARAMETER: &str = "key";
+// the room code that makes a new room instead
+#[cfg(feature = "native")]
+pub const NEW_ROOM: &str = "new";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MovePaddle {
     pub player_id: u64,
@@ -20,8 +29,15 @@ pub struct MovePaddle {
 pub enum ClientMessage {
     //StopMoving, StartMovingLeft, StartMovingRight
     MovePaddle(MovePaddle),
+    /// what the player wants to be called, sent after the welcome
+    SetName(String),
+    /// only from the host of a private room before its match starts
+    SetRules(Rules),
+    /// only from the host of a private room
+    StartMatch,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 struct PlayerState {
     id: u32,
@@ -29,10 +45,61 @@ struct PlayerState {
     velocity: Vec2,
 }
 
+/// How a match is played, the server sends them to every player
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    pub lives: u32,
+    pub ball_speed: f32,
+    pub paddle_speed: f32,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            lives: 3,
+            ball_speed: 300.0,
+            paddle_speed: 500.0,
+        }
+    }
+}
+
+/// A private room, joined with its code
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Room {
+    pub code: String,
+    /// the player who sets the rules and starts the match
+    pub host: u64,
+    pub started: bool,
+}
+
+/// Where a player waiting for a public match stands
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueueStatus {
+    pub rating: u32,
+    /// everyone in the queue, including the player
+    pub waiting: usize,
+    /// seconds until a match is likely found, `None` before anyone of similar skill got one
+    pub estimated_wait: Option<f32>,
+}
+
 // server
+#[allow(clippy::enum_variant_names)]
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ServerMessage {
+    /// sent only to the player that connected, with the id everyone else knows it by
+    Welcome(u64),
     PlayerStateUpdate(MovePaddle),
+    /// where every paddle is, sent to a player that resumed its session
+    Snapshot(Vec<MovePaddle>),
     PlayerConnected(u64),
     PlayerDisconnected(u64),
+    /// the rules of the player's match, sent after the welcome and when the host changes them
+    Rules(Rules),
+    /// sent to everyone in a private room when someone joins and when it changes
+    Room(Room),
+    /// why the room the player asked for can't be joined, the server hangs up after it
+    RoomUnavailable(String),
+    /// sent while the player waits for a public match, the welcome follows when there is one
+    Queued(QueueStatus),
 }
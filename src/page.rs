@@ -0,0 +1,56 @@
+use web_sys::{UrlSearchParams, Window};
+
+use crate::WEBSOCKET_PATH;
+
+// the environment variables that can also be set in the page's address, `?server=wss://...`
+const QUERY_PARAMETERS: &[(&str, &str)] = &[
+    ("PONG_SERVER_URL", "server"),
+    ("PONG_PLAYER_NAME", "name"),
+    ("PONG_KEY", "key"),
+    ("PONG_ROOM", "room"),
+    ("PONG_RULES", "rules"),
+];
+
+/// Stands in for the environment in the browser. A setting comes from the address,
+/// then from `window.PONG_*` set in index.html, and the server falls back to the one
+/// the page was served by
+pub fn var(name: &str) -> Option<String> {
+    let window = web_sys::window()?;
+    query_parameter(&window, name)
+        .or_else(|| global(&window, name))
+        .or_else(|| match name {
+            "PONG_SERVER_URL" => served_by(&window),
+            _ => None,
+        })
+}
+
+fn query_parameter(window: &Window, name: &str) -> Option<String> {
+    let (_, parameter) = QUERY_PARAMETERS
+        .iter()
+        .find(|(variable, _)| *variable == name)?;
+    let search = window.location().search().ok()?;
+    UrlSearchParams::new_with_str(&search)
+        .ok()?
+        .get(parameter)
+        .filter(|value| !value.is_empty())
+}
+
+fn global(window: &Window, name: &str) -> Option<String> {
+    js_sys::Reflect::get(window, &js_sys::JsString::from(name))
+        .ok()?
+        .as_string()
+        .filter(|value| !value.is_empty())
+}
+
+// the game server serves the web client next to the socket, over wss:// when the page is https
+fn served_by(window: &Window) -> Option<String> {
+    let location = window.location();
+    let scheme = match location.protocol().ok()?.as_str() {
+        "https:" => "wss",
+        "http:" => "ws",
+        // opened from disk, there's no server to go by
+        _ => return None,
+    };
+    let host = location.host().ok()?;
+    Some(format!("{}://{}{}", scheme, host, WEBSOCKET_PATH))
+}
@@ -0,0 +1,124 @@
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy_ws::{WebsocketClientEvent, WebsocketResource};
+use log::{error, info};
+
+use crate::cli::Args;
+use crate::network::{
+    ClientMessage, Room, ServerMessage, KEY_QUERY_PARAMETER, PLAYER_QUERY_PARAMETER,
+    ROOM_QUERY_PARAMETER,
+};
+
+/// Follows the private room the player is in, hands the server the `--rules` when hosting
+/// and gives up when the room can't be joined
+pub struct RoomPlugin;
+
+impl Plugin for RoomPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CurrentRoom>();
+        app.add_system(follow_room);
+    }
+}
+
+#[derive(Default)]
+pub struct CurrentRoom {
+    pub room: Option<Room>,
+    player_id: Option<u64>,
+}
+
+impl CurrentRoom {
+    pub fn is_host(&self) -> bool {
+        matches!((&self.room, self.player_id), (Some(room), Some(player_id)) if room.host == player_id)
+    }
+}
+
+/// The server with the room from `--room`, or once in one the room's code so
+/// reconnecting doesn't make another room, and the `--name` and `--key` to log in with
+pub fn server_address(args: &Args, current: &CurrentRoom) -> String {
+    let room = match &current.room {
+        Some(room) => Some(room.code.as_str()),
+        None => args.room.as_deref(),
+    };
+    let mut query = vec![];
+    if let Some(code) = room {
+        query.push((ROOM_QUERY_PARAMETER, code));
+    }
+    if let (Some(name), Some(key)) = (&args.player_name, &args.key) {
+        query.push((PLAYER_QUERY_PARAMETER, name.as_str()));
+        query.push((KEY_QUERY_PARAMETER, key.as_str()));
+    }
+    let mut address = args.server_url.clone();
+    if query.is_empty() {
+        return address;
+    }
+    let authority_start = address.find("://").map_or(0, |start| start + 3);
+    let authority_end = address[authority_start..]
+        .find(['/', '?'])
+        .map_or(address.len(), |end| authority_start + end);
+    // the query string needs a path in front of it, `ws://localhost:8080/?room=...`
+    if !address[authority_end..].starts_with('/') {
+        address.insert(authority_end, '/');
+    }
+    for (name, value) in query {
+        let separator = if address.contains('?') { '&' } else { '?' };
+        let value: String = form_urlencoded::byte_serialize(value.as_bytes()).collect();
+        address = format!("{}{}{}={}", address, separator, name, value);
+    }
+    address
+}
+
+fn follow_room(
+    net: Res<WebsocketResource>,
+    args: Res<Args>,
+    mut current: ResMut<CurrentRoom>,
+    mut network_event_reader: EventReader<WebsocketClientEvent>,
+    mut exit: EventWriter<AppExit>,
+) {
+    for event in network_event_reader.iter() {
+        let msg = match event {
+            WebsocketClientEvent::OnMessage(msg) => msg,
+            _ => continue,
+        };
+        match serde_json::from_str(msg) {
+            Ok(ServerMessage::Welcome(player_id)) => current.player_id = Some(player_id),
+            Ok(ServerMessage::Room(room)) => {
+                let was_host = current.is_host();
+                let previous = current.room.replace(room.clone());
+                if previous.is_none() {
+                    info!("In room {}, others join with the same code", room.code);
+                }
+                if room.started && !previous.is_some_and(|previous| previous.started) {
+                    info!("Room {} started", room.code);
+                }
+                if current.is_host() && !was_host && !room.started {
+                    info!("Hosting room {}, press Enter to start", room.code);
+                    if let Some(rules) = &args.rules {
+                        let msg = ClientMessage::SetRules(rules.clone());
+                        let str = serde_json::to_string(&msg).expect("unable to serialize json");
+                        net.broadcast(str);
+                    }
+                }
+            }
+            Ok(ServerMessage::RoomUnavailable(reason)) => {
+                error!("{}", reason);
+                exit.send(AppExit);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Enter starts the match of a room the player hosts
+#[cfg(feature = "graphics")]
+pub fn start_match_on_enter(
+    keyboard_input: Res<Input<KeyCode>>,
+    net: Res<WebsocketResource>,
+    current: Res<CurrentRoom>,
+) {
+    let waiting = current.room.as_ref().is_some_and(|room| !room.started);
+    if waiting && current.is_host() && keyboard_input.just_pressed(KeyCode::Return) {
+        let msg = ClientMessage::StartMatch;
+        let str = serde_json::to_string(&msg).expect("unable to serialize json");
+        net.broadcast(str);
+    }
+}